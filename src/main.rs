@@ -2,7 +2,7 @@ use cgmath::Vector2;
 use error_iter::ErrorIter as _;
 use log::error;
 use pixels::{Error, Pixels, SurfaceTexture};
-use std::rc::Rc;
+use std::sync::Arc;
 use std::time::Instant;
 use winit::{
     dpi::LogicalSize,
@@ -12,13 +12,39 @@ use winit::{
 };
 use winit_input_helper::WinitInputHelper;
 
-mod raycaster;
+use raycaster_cpu::raycaster;
 
 const WIDTH: usize = 600;
 const HEIGHT: usize = 500;
 
+const USAGE: &str = "usage: raycaster-cpu [MAP]
+       raycaster-cpu render MAP OUTPUT [--pos X Y] [--angle DEGREES] [--z Z]
+                                       [--pitch PITCH] [--fov DEGREES]
+                                       [--size WIDTH HEIGHT]
+                                       [--filter nearest|bilinear|trilinear]
+                                       [--projection planar|cylindrical|fisheye]
+                                       [--time SECONDS]
+       raycaster-cpu bake MAP [--resolution TEXELS]";
+
 fn main() -> Result<(), Error> {
     env_logger::init();
+
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().map(String::as_str) == Some("render") {
+        if let Err(err) = render_headless(&args[1..]) {
+            eprintln!("error: {err}\n{USAGE}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if args.first().map(String::as_str) == Some("bake") {
+        if let Err(err) = bake(&args[1..]) {
+            eprintln!("error: {err}\n{USAGE}");
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+
     let event_loop = EventLoop::new();
     let mut input = WinitInputHelper::new();
 
@@ -35,6 +61,7 @@ fn main() -> Result<(), Error> {
 
     let mut last_frame_time = Instant::now();
     let mut delta_time = 0.0;
+    let start_time = Instant::now();
 
     let mut pixels = {
         let window_size = window.inner_size();
@@ -43,92 +70,45 @@ fn main() -> Result<(), Error> {
     };
 
     let mut camera = raycaster::Camera::new(Vector2::new(5.0, 5.0), 0.0, 60f64.to_radians());
-    let size = 10;
-    let mut map = raycaster::Map::new(size, size, 1.0);
-
-    let texture = Rc::new(raycaster::Texture::new("textures/wall1.png"));
-    let wall = raycaster::Tile::new(
-        raycaster::Shape::Box,
-        vec![
-            raycaster::Color::Test2,
-            raycaster::Color::Test,
-            raycaster::Color::Texture(texture),
-            raycaster::Color::Test,
-        ],
-        raycaster::Color::Test,
-        0.0,
-        raycaster::Color::Test,
-        1.0,
-    );
-    for i in 0..size {
-        map.set_tile(i, 0, wall.clone());
-        map.set_tile(i, size - 1, wall.clone());
-        map.set_tile(0, i, wall.clone());
-        map.set_tile(size - 1, i, wall.clone());
-    }
+    let map_path = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| "maps/demo.map".to_owned());
+    let mut map = match raycaster::Map::load(&map_path) {
+        Ok(map) => map,
+        Err(err) => {
+            log_error("Map::load", err);
+            std::process::exit(1);
+        }
+    };
+
+    let sprites = vec![
+        raycaster::Sprite {
+            pos: Vector2::new(3.5, 3.5),
+            color: raycaster::Color::Test2,
+            size: Vector2::new(0.5, 0.5),
+            z: 0.0,
+        },
+        raycaster::Sprite {
+            pos: Vector2::new(7.5, 3.5),
+            color: raycaster::Color::texture(Arc::new(raycaster::Texture::new(
+                "textures/wall1.png",
+            ))),
+            size: Vector2::new(0.4, 0.4),
+            z: 0.3,
+        },
+    ];
 
-    let wall2 = raycaster::Tile::new(
-        raycaster::Shape::Circle(raycaster::Circle {
-            pos: Vector2 { x: 0.5, y: 0.5 },
-            radius: 0.5,
-        }),
-        vec![raycaster::Color::Test2],
-        raycaster::Color::Test,
-        0.0,
-        raycaster::Color::Test,
-        1.0,
-    );
-    map.set_tile(5, 5, wall2);
-
-    let wall3 = raycaster::Tile::new(
-        raycaster::Shape::AxisAlignedBox(raycaster::AxisAlignedBox {
-            min: Vector2 { x: 0.2, y: 0.2 },
-            max: Vector2 { x: 0.3, y: 0.8 },
-        }),
-        vec![
-            raycaster::Color::Test,
-            raycaster::Color::Solid([1.0, 1.0, 1.0, 0.0]),
-            raycaster::Color::Test,
-            raycaster::Color::Test,
-        ],
-        raycaster::Color::Test,
-        0.0,
-        raycaster::Color::Test,
-        1.0,
-    );
-    map.set_tile(6, 5, wall3);
-
-    let wall4 = raycaster::Tile::new(
-        raycaster::Shape::Line(raycaster::Line::new(
-            Vector2 { x: 0.0, y: 0.0 },
-            Vector2 { x: 1.0, y: 1.0 },
-        )),
-        vec![raycaster::Color::Test2, raycaster::Color::Test],
-        raycaster::Color::Test,
-        0.0,
-        raycaster::Color::Test,
-        1.0,
-    );
-    map.set_tile(7, 5, wall4);
-
-    let wall5 = raycaster::Tile::new(
-        raycaster::Shape::Void,
-        vec![],
-        raycaster::Color::Test,
-        1.0,
-        raycaster::Color::Test,
-        0.5,
-    );
-    map.set_tile(4, 4, wall5);
-
-    let mut renderer = raycaster::Renderer::new(WIDTH, HEIGHT);
+    let mut renderer = new_renderer(WIDTH, HEIGHT);
 
     event_loop.run(move |event, _, control_flow| {
         if let Event::RedrawRequested(_) = event {
             delta_time = last_frame_time.elapsed().as_secs_f64();
             println!("Delta time: {}ms", delta_time * 1000.0);
             last_frame_time = Instant::now();
-            renderer.render(pixels.frame_mut(), &camera, &map);
+            map.update(delta_time);
+            let time = start_time.elapsed().as_secs_f64();
+            renderer.render(pixels.frame_mut(), &camera, &map, &sprites, time);
             if let Err(err) = pixels.render() {
                 log_error("pixels.render", err);
                 *control_flow = ControlFlow::Exit;
@@ -146,12 +126,28 @@ fn main() -> Result<(), Error> {
             }
 
             const MOVE_SPEED: f64 = 3.0;
+            const PLAYER_RADIUS: f64 = 0.2;
+            const STEP_HEIGHT: f64 = 0.3;
+            let from = camera.pos();
             if input.key_held(VirtualKeyCode::W) {
                 camera.translate(Vector2::new(0.0, MOVE_SPEED * delta_time));
             }
             if input.key_held(VirtualKeyCode::S) {
                 camera.translate(Vector2::new(0.0, -MOVE_SPEED * delta_time));
             }
+            let z = camera.z();
+            let to = map.move_circle(from, camera.pos(), PLAYER_RADIUS, z, STEP_HEIGHT);
+            camera.set_pos(to);
+            // Keep the eye height while walking up and down ramps and steps.
+            if let (Some(old), Some(new)) =
+                (map.floor_height_at(from, z), map.floor_height_at(to, z))
+            {
+                camera.translate_z(new - old);
+            }
+            if let Some((pos, rotation)) = map.portal_crossing(from, to) {
+                camera.set_pos(pos);
+                camera.rotate(rotation);
+            }
 
             const ROT_SPEED: f64 = 2.0;
             if input.key_held(VirtualKeyCode::D) {
@@ -161,6 +157,30 @@ fn main() -> Result<(), Error> {
                 camera.rotate(-ROT_SPEED * delta_time);
             }
 
+            if input.key_pressed(VirtualKeyCode::E) {
+                let front = camera.pos() + camera.dir();
+                let (x, y) = (front.x.floor() as i32, front.y.floor() as i32);
+                if x >= 0 && y >= 0 {
+                    let (x, y) = (x as usize, y as usize);
+                    if let Some(state) = map.door_state(x, y) {
+                        let state = match state {
+                            raycaster::DoorState::Open => raycaster::DoorState::Closed,
+                            raycaster::DoorState::Closed => raycaster::DoorState::Open,
+                        };
+                        map.set_door_state(x, y, state).unwrap();
+                    }
+                }
+            }
+
+            if input.key_pressed(VirtualKeyCode::P) {
+                use raycaster::Projection;
+                camera.set_projection(match camera.projection() {
+                    Projection::Planar => Projection::Cylindrical,
+                    Projection::Cylindrical => Projection::Fisheye,
+                    Projection::Fisheye => Projection::Planar,
+                });
+            }
+
             const Z_SPEED: f64 = 5.0;
             if input.key_held(VirtualKeyCode::Up) {
                 camera.translate_z(Z_SPEED * delta_time);
@@ -169,6 +189,20 @@ fn main() -> Result<(), Error> {
                 camera.translate_z(-Z_SPEED * delta_time);
             }
 
+            const LOOK_SPEED: f64 = 1.0;
+            const MOUSE_SENSITIVITY: f64 = 0.002;
+            if input.key_held(VirtualKeyCode::PageUp) {
+                camera.look(LOOK_SPEED * delta_time);
+            }
+            if input.key_held(VirtualKeyCode::PageDown) {
+                camera.look(-LOOK_SPEED * delta_time);
+            }
+            if input.mouse_held(0) {
+                let (mouse_x, mouse_y) = input.mouse_diff();
+                camera.rotate(mouse_x as f64 * MOUSE_SENSITIVITY);
+                camera.look(-mouse_y as f64 * MOUSE_SENSITIVITY);
+            }
+
             if let Some(size) = input.window_resized() {
                 if let Err(err) = pixels.resize_surface(size.width, size.height) {
                     log_error("pixels.resize_surface", err);
@@ -181,6 +215,117 @@ fn main() -> Result<(), Error> {
     });
 }
 
+fn new_renderer(width: usize, height: usize) -> raycaster::Renderer {
+    let mut renderer = raycaster::Renderer::new(width, height);
+    renderer.lighting.fog.falloff = raycaster::Falloff::Exponential { density: 0.15 };
+    renderer.lighting.side_shade = 0.75;
+    renderer.filter = raycaster::Filter::Trilinear;
+    renderer
+}
+
+/// Renders a single frame of a map to an image file without opening a window.
+fn render_headless(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let (map_path, output) = match args {
+        [map_path, output, ..] => (map_path, output),
+        _ => return Err("missing MAP or OUTPUT".into()),
+    };
+    let map = raycaster::Map::load(map_path)?;
+
+    let mut pos = Vector2::new(map.width() as f64 / 2.0, map.height() as f64 / 2.0);
+    let mut angle = 0.0;
+    let mut z = 0.0;
+    let mut pitch = 0.0;
+    let mut fov = 60.0;
+    let mut size = (WIDTH, HEIGHT);
+    let mut filter = raycaster::Filter::Trilinear;
+    let mut projection = raycaster::Projection::Planar;
+    let mut time = 0.0;
+
+    let mut options = args[2..].iter();
+    while let Some(option) = options.next() {
+        let mut value = |name: &str| -> Result<f64, Box<dyn std::error::Error>> {
+            let value = options
+                .next()
+                .ok_or_else(|| format!("missing value for {name}"))?;
+            value
+                .parse()
+                .map_err(|_| format!("invalid value `{value}` for {name}").into())
+        };
+        match option.as_str() {
+            "--pos" => pos = Vector2::new(value("--pos")?, value("--pos")?),
+            "--angle" => angle = value("--angle")?,
+            "--z" => z = value("--z")?,
+            "--pitch" => pitch = value("--pitch")?,
+            "--fov" => fov = value("--fov")?,
+            "--time" => time = value("--time")?,
+            "--size" => {
+                size = (value("--size")? as usize, value("--size")? as usize);
+                if size.0 == 0 || size.1 == 0 {
+                    return Err("--size must be at least 1 by 1".into());
+                }
+            }
+            "--filter" => {
+                filter = match options.next().map(String::as_str) {
+                    Some("nearest") => raycaster::Filter::Nearest,
+                    Some("bilinear") => raycaster::Filter::Bilinear,
+                    Some("trilinear") => raycaster::Filter::Trilinear,
+                    Some(other) => {
+                        return Err(format!("invalid value `{other}` for --filter").into())
+                    }
+                    None => return Err("missing value for --filter".into()),
+                }
+            }
+            "--projection" => {
+                projection = match options.next().map(String::as_str) {
+                    Some("planar") => raycaster::Projection::Planar,
+                    Some("cylindrical") => raycaster::Projection::Cylindrical,
+                    Some("fisheye") => raycaster::Projection::Fisheye,
+                    Some(other) => {
+                        return Err(format!("invalid value `{other}` for --projection").into())
+                    }
+                    None => return Err("missing value for --projection".into()),
+                }
+            }
+            other => return Err(format!("unknown option `{other}`").into()),
+        }
+    }
+
+    let mut camera = raycaster::Camera::new(pos, f64::to_radians(angle), f64::to_radians(fov));
+    camera.set_projection(projection);
+    camera.translate_z(z);
+    camera.look(pitch);
+    let mut renderer = new_renderer(size.0, size.1);
+    renderer.filter = filter;
+    renderer.render_to_file(output, &camera, &map, &[], time)?;
+    Ok(())
+}
+
+/// Bakes the static lights of a map into a lightmap stored next to it.
+fn bake(args: &[String]) -> Result<(), Box<dyn std::error::Error>> {
+    let map_path = args.first().ok_or("missing MAP")?;
+    let mut resolution = 4;
+    let mut options = args[1..].iter();
+    while let Some(option) = options.next() {
+        match option.as_str() {
+            "--resolution" => {
+                let value = options.next().ok_or("missing value for --resolution")?;
+                resolution = match value.parse() {
+                    Ok(resolution) if resolution > 0 => resolution,
+                    _ => return Err(format!("invalid value `{value}` for --resolution").into()),
+                };
+            }
+            other => return Err(format!("unknown option `{other}`").into()),
+        }
+    }
+
+    let map = raycaster::Map::load(map_path)?;
+    let lightmap = raycaster::Lightmap::bake(&map, resolution);
+    let path = raycaster::Lightmap::path_for(std::path::Path::new(map_path));
+    lightmap.save(&path)?;
+    println!("wrote {}", path.display());
+    Ok(())
+}
+
 fn log_error<E: std::error::Error + 'static>(method_name: &str, err: E) {
     error!("{method_name}() failed: {err}");
     for source in err.sources().skip(1) {
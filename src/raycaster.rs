@@ -1,4 +1,7 @@
-use cgmath::Vector2;
+use std::f64::consts::PI;
+use std::ops::Range;
+
+use cgmath::{InnerSpace, Vector2, Vector3};
 
 pub mod camera;
 pub use camera::*;
@@ -6,10 +9,28 @@ pub use camera::*;
 pub mod map;
 pub use map::*;
 
+pub mod sprite;
+pub use sprite::*;
+
+pub mod lighting;
+pub use lighting::*;
+
+pub mod headless;
+
+pub mod error;
+pub use error::*;
+
+const NEAR_PLANE: f64 = 0.01;
+
 pub struct Renderer {
     width: usize,
     height: usize,
     temp_screen: Vec<[f64; 4]>,
+    pub lighting: Lighting,
+    /// Number of threads the columns are split between.
+    pub threads: usize,
+    /// Texture filtering used for walls, floors, ceilings and sprites.
+    pub filter: Filter,
 }
 
 impl Renderer {
@@ -20,93 +41,398 @@ impl Renderer {
             width,
             height,
             temp_screen,
+            lighting: Lighting::default(),
+            threads: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            filter: Filter::Nearest,
         }
     }
 
-    pub fn render(&mut self, screen: &mut [u8], camera: &Camera, map: &Map) {
-        for i in 0..self.width * self.height {
-            self.temp_screen[i] = [0.0, 0.0, 0.0, 1.0];
+    /// Renders a frame. `time` is in seconds and drives animated colors.
+    pub fn render(
+        &mut self,
+        screen: &mut [u8],
+        camera: &Camera,
+        map: &Map,
+        sprites: &[Sprite],
+        time: f64,
+    ) {
+        if self.width == 0 || self.height == 0 {
+            return;
+        }
+        let projected = self.project_sprites(camera, map, sprites);
+        let rays: Vec<Vector2<f64>> = camera.rays(self.width as u32).collect();
+
+        // The temporary screen is column major, so every thread gets a
+        // contiguous range of columns.
+        let threads = self.threads.clamp(1, self.width.max(1));
+        let columns_per_thread = self.width.div_ceil(threads);
+        let height = self.height;
+        let horizon = self.horizon(camera);
+        let lighting = &self.lighting;
+        let filter = self.filter;
+        let projection = camera.projection();
+        std::thread::scope(|scope| {
+            for (i, chunk) in self
+                .temp_screen
+                .chunks_mut(columns_per_thread * height)
+                .enumerate()
+            {
+                let first_x = i * columns_per_thread;
+                let rays = &rays[first_x..first_x + chunk.len() / height];
+                let projected = &projected;
+                scope.spawn(move || {
+                    let mut columns = Columns {
+                        screen: chunk,
+                        first_x,
+                        height,
+                        horizon,
+                        projection,
+                        lighting,
+                        filter,
+                        time,
+                        clip: 0..height,
+                    };
+                    for (x, ray_dir) in rays.iter().enumerate() {
+                        columns.render_column(first_x + x, *ray_dir, camera, map, projected);
+                    }
+                });
+            }
+        });
+
+        let rows_per_thread = self.height.div_ceil(threads);
+        let width = self.width;
+        let temp_screen = &self.temp_screen;
+        // Only the start of a larger buffer is written to.
+        let screen = &mut screen[..width * height * 4];
+        std::thread::scope(|scope| {
+            for (i, chunk) in screen.chunks_mut(rows_per_thread * width * 4).enumerate() {
+                scope.spawn(move || {
+                    for (j, pixel) in chunk.chunks_mut(4).enumerate() {
+                        let x = j % width;
+                        let y = i * rows_per_thread + j / width;
+                        let color = temp_screen[x * height + y];
+                        for c in 0..3 {
+                            pixel[c] = (color[c] * 255.0) as u8;
+                        }
+                        pixel[3] = 255;
+                    }
+                });
+            }
+        });
+    }
+
+    /// Returns the screen row of the horizon, which pitching the camera moves
+    /// up or down.
+    fn horizon(&self, camera: &Camera) -> f64 {
+        self.height as f64 * (0.5 + camera.pitch())
+    }
+
+    /// Projects the sprites onto the screen and sorts them from near to far.
+    /// Their depth is measured like the distances of the walls for the
+    /// projection of the camera, so that both are drawn in the right order.
+    fn project_sprites<'a>(
+        &self,
+        camera: &Camera,
+        map: &Map,
+        sprites: &'a [Sprite],
+    ) -> Vec<ProjectedSprite<'a>> {
+        let dir = camera.dir();
+        let plane = camera.plane();
+        let inv_det = 1.0 / (dir.x * plane.y - plane.x * dir.y);
+        let w = self.width as f64;
+        let h = self.height as f64;
+        let horizon = self.horizon(camera);
+
+        let mut projected: Vec<ProjectedSprite> = sprites
+            .iter()
+            .filter_map(|sprite| {
+                let rel = sprite.pos - camera.pos();
+                // Where the center of the sprite is on the screen, from -1 at
+                // the left edge to 1 at the right one, and its half width.
+                let (depth, camera_x, half_width) = match camera.projection() {
+                    Projection::Planar => {
+                        let depth = inv_det * (rel.x * plane.y - plane.x * rel.y);
+                        let camera_x = inv_det * (dir.x * rel.y - rel.x * dir.y) / depth;
+                        let half_width = sprite.size.x / (2.0 * plane.magnitude() * depth);
+                        (depth, camera_x, half_width)
+                    }
+                    Projection::Cylindrical | Projection::Fisheye => {
+                        let depth = rel.magnitude();
+                        let right = Vector2::new(-dir.y, dir.x);
+                        let angle = rel.dot(right).atan2(rel.dot(dir));
+                        let half_fov = camera.fov() / 2.0;
+                        let half_width = (sprite.size.x / (2.0 * depth)).atan() / half_fov;
+                        (depth, angle / half_fov, half_width)
+                    }
+                };
+                if depth <= NEAR_PLANE {
+                    return None;
+                }
+                Some(ProjectedSprite {
+                    depth,
+                    start_x: (camera_x - half_width + 1.0) * w / 2.0,
+                    end_x: (camera_x + half_width + 1.0) * w / 2.0,
+                    start_y: horizon + (camera.z() - sprite.z - sprite.size.y) * h / depth,
+                    end_y: horizon + (camera.z() - sprite.z) * h / depth,
+                    color: &sprite.color,
+                    light: map
+                        .get_tile(sprite.pos.x.floor() as i32, sprite.pos.y.floor() as i32)
+                        .map_or(1.0, |tile| tile.light),
+                })
+            })
+            .collect();
+        projected.sort_by(|a, b| a.depth.total_cmp(&b.depth));
+        projected
+    }
+}
+
+/// A contiguous range of columns of the temporary screen.
+struct Columns<'a> {
+    screen: &'a mut [[f64; 4]],
+    first_x: usize,
+    height: usize,
+    horizon: f64,
+    projection: Projection,
+    lighting: &'a Lighting,
+    filter: Filter,
+    time: f64,
+    /// The rows that may be drawn on, so that what is seen in a mirror that
+    /// is shorter than the walls only shows where the mirror is.
+    clip: Range<usize>,
+}
+
+/// A mirror that a ray was reflected by.
+struct Mirror {
+    /// How many portals and mirrors the ray went through before the mirror.
+    redirects: usize,
+    dist: f64,
+    /// The rows the mirror covers where it can be seen.
+    rows: Range<usize>,
+    /// The direction of the reflected ray. The sky that shows through the
+    /// mirror is the one in that direction.
+    dir: Vector2<f64>,
+    /// Whether the mirror is shorter than the walls, so that the ray carries
+    /// on above or below it.
+    partial: bool,
+}
+
+impl Columns<'_> {
+    fn render_column(
+        &mut self,
+        x: usize,
+        ray_dir: Vector2<f64>,
+        camera: &Camera,
+        map: &Map,
+        projected: &[ProjectedSprite],
+    ) {
+        let start = (x - self.first_x) * self.height;
+        for pixel in &mut self.screen[start..start + self.height] {
+            *pixel = [0.0, 0.0, 0.0, 1.0];
         }
 
-        let mut x = 0;
-        let pos = camera.pos();
-        for ray_dir in camera.rays(self.width as u32) {
-            let mut left = self.height;
-            map.ray_cast(pos, ray_dir, &mut |hit| match hit {
+        let mut left = self.height;
+        let mut next_sprite = 0;
+        // The sprites are in the camera's map, so a portal or mirror hides
+        // the ones behind it.
+        let mut sprite_limit = f64::INFINITY;
+        // The mirrors whose reflection is being drawn.
+        let mut mirrors: Vec<Mirror> = Vec::new();
+        let sky = map.sky.as_ref();
+        map.ray_cast_at_height(camera.pos(), camera.z(), ray_dir, &mut |hit| {
+            let depth = match &hit {
+                Hit::WallHit(wall_hit) => wall_hit.length,
+                Hit::FloorHit(floor_hit) => floor_hit.dist2,
+            };
+            // The reflection ends at a hit behind the mirror that is not in
+            // it. A mirror seen in another mirror is inside of it, so the last
+            // one is closed first.
+            while mirrors
+                .last()
+                .is_some_and(|mirror| mirror.redirects >= hit.redirects() && depth > mirror.dist)
+            {
+                let mirror = mirrors.pop().unwrap();
+                left -= self.close_mirror(x, sky, mirror);
+            }
+            self.clip = mirrors
+                .iter()
+                .rev()
+                .find(|mirror| mirror.partial)
+                .map_or(0..self.height, |mirror| mirror.rows.clone());
+            // Sprites in front of the hit are drawn first to keep the
+            // front to back order that set_pixel relies on.
+            sprite_limit = hit.redirect_dist().unwrap_or(f64::INFINITY);
+            let limit = depth.min(sprite_limit);
+            while next_sprite < projected.len() && projected[next_sprite].depth < limit {
+                left -= self.render_sprite(x, &projected[next_sprite]);
+                next_sprite += 1;
+            }
+            if left == 0 {
+                return true;
+            }
+
+            match hit {
                 Hit::WallHit(wall_hit) => {
-                    left -= self.render_wall(x, &wall_hit, camera, map.wall_height);
-                    left == 0
+                    left -= self.render_wall(x, &wall_hit, camera, map);
+                    if let Color::Reflective { .. } = wall_hit.color {
+                        let (dist, bottom, top) = (wall_hit.length, wall_hit.bottom, wall_hit.top);
+                        let rows = self.screen_rows(self.wall_rows(dist, bottom, top, camera));
+                        let start = rows.start.max(self.clip.start);
+                        let end = rows.end.min(self.clip.end).max(start);
+                        let (dir, normal) = (wall_hit.dir, wall_hit.normal);
+                        mirrors.push(Mirror {
+                            redirects: wall_hit.redirects,
+                            dist,
+                            rows: start..end,
+                            dir: dir - 2.0 * dir.dot(normal) * normal,
+                            partial: bottom > 0.0 || top < map.wall_height,
+                        });
+                    }
                 }
                 Hit::FloorHit(floor_hit) => {
-                    left -= self.render_floor(x, &floor_hit, camera);
-                    left -= self.render_ceiling(x, &floor_hit, camera);
-
-                    left == 0
+                    left -= self.render_floor(x, &floor_hit, camera, map);
+                    left -= self.render_ceiling(x, &floor_hit, camera, map);
                 }
-            });
+            }
+            left == 0
+        });
+        while let Some(mirror) = mirrors.pop() {
+            left -= self.close_mirror(x, sky, mirror);
+        }
+        self.clip = 0..self.height;
+        for sprite in &projected[next_sprite..] {
+            if sprite.depth >= sprite_limit {
+                break;
+            }
+            left -= self.render_sprite(x, sprite);
+        }
+        if let (Some(sky), true) = (sky, left > 0) {
+            self.render_sky(x, sky, ray_dir, 0..self.height);
+        }
+    }
 
-            x += 1;
+    /// Draws the sky in the rows of a mirror that its reflection left
+    /// transparent. What is behind a partial mirror is then hidden where it
+    /// is, as the ray carries on past it. Returns how many pixels were
+    /// finished.
+    fn close_mirror(&mut self, x: usize, sky: Option<&Color>, mirror: Mirror) -> usize {
+        let Mirror {
+            rows, dir, partial, ..
+        } = mirror;
+        let start = (x - self.first_x) * self.height;
+        let unfinished = |screen: &[[f64; 4]]| {
+            rows.clone()
+                .filter(|&y| screen[start + y][3] != 0.0)
+                .count()
+        };
+        let before = unfinished(self.screen);
+        if let Some(sky) = sky {
+            self.clip = rows.clone();
+            self.render_sky(x, sky, dir, rows.clone());
         }
+        if partial {
+            for pixel in &mut self.screen[start + rows.start..start + rows.end] {
+                pixel[3] = 0.0;
+            }
+        }
+        before - unfinished(self.screen)
+    }
 
-        for y in 0..self.height {
-            for x in 0..self.width {
-                let index1 = x * self.height + y;
-                let index2 = (y * self.width + x) * 4;
-                for i in 0..3 {
-                    screen[index2 + i] = (self.temp_screen[index1][i] * 255.0) as u8;
-                }
-                screen[index2 + 3] = 255;
+    /// Fills what is still transparent in `rows` of the column with the sky,
+    /// which is infinitely far away and so not affected by fog.
+    fn render_sky(&mut self, x: usize, sky: &Color, ray_dir: Vector2<f64>, rows: Range<usize>) {
+        let h = self.height as f64;
+        let u = (ray_dir.y.atan2(ray_dir.x) / (2.0 * PI)).rem_euclid(1.0);
+        let sampler = Sampler {
+            filter: self.filter,
+            time: self.time,
+            footprint: 1.0 / (PI * h),
+        };
+        for y in rows {
+            if !self.pixel_finished(x, y) {
+                // The screen is one unit of depth away and `h` pixels tall.
+                let angle = ((self.planar_row(y as f64 + 0.5) - self.horizon) / h).atan();
+                let color = sky.sample(Vector2::new(u, 0.5 + angle / PI), &sampler);
+                self.set_pixel(x, y, color);
             }
         }
     }
 
     fn set_pixel(&mut self, x: usize, y: usize, color: [f64; 4]) -> bool {
-        let index = x * self.height + y;
+        let index = (x - self.first_x) * self.height + y;
         for i in 0..3 {
-            self.temp_screen[index][i] += self.temp_screen[index][3] * color[3] * color[i];
-        }
-        self.temp_screen[index][3] *= 1.0 - color[3];
-        if self.temp_screen[index][3] == 0.0 {
-            true
-        } else {
-            false
+            self.screen[index][i] += self.screen[index][3] * color[3] * color[i];
         }
+        self.screen[index][3] *= 1.0 - color[3];
+        self.screen[index][3] == 0.0
     }
 
+    /// Pixels outside of `clip` count as finished.
     fn pixel_finished(&self, x: usize, y: usize) -> bool {
-        let index = x * self.height + y;
-        if self.temp_screen[index][3] == 0.0 {
-            true
-        } else {
-            false
-        }
+        let index = (x - self.first_x) * self.height + y;
+        self.screen[index][3] == 0.0 || !self.clip.contains(&y)
     }
 
-    fn render_wall(
-        &mut self,
-        x: usize,
-        wall_hit: &WallHit,
-        camera: &Camera,
-        wall_height: f64,
-    ) -> usize {
-        let line_height = (self.height as f64 / wall_hit.length * wall_height) as i32;
-        let mid_point = (self.height as i32) / 2
-            + ((camera.z() * 2.0 - wall_height) * self.height as f64 / (2.0 * wall_hit.length))
-                as i32;
-        //println!("{} {} {}", camera.z(), line_height, mid_point);
-        let start = -line_height / 2 + mid_point;
-        let end = line_height / 2 + mid_point;
+    /// Returns the rows of the planar projection that a wall from `bottom` to
+    /// `top` at `dist` spans, which may be off the screen.
+    fn wall_rows(&self, dist: f64, bottom: f64, top: f64, camera: &Camera) -> (i32, i32) {
+        let line_height = (self.height as f64 / dist * (top - bottom)) as i32;
+        let mid_point = self.horizon as i32
+            + ((camera.z() * 2.0 - top - bottom) * self.height as f64 / (2.0 * dist)) as i32;
+        (-line_height / 2 + mid_point, line_height / 2 + mid_point)
+    }
+
+    /// Returns the screen rows between two rows of the planar projection.
+    fn screen_rows(&self, (start, end): (i32, i32)) -> Range<usize> {
+        let h = self.height as f64;
+        let start = self.screen_row(start as f64).clamp(0.0, h) as usize;
+        let end = self.screen_row(end as f64).clamp(0.0, h) as usize;
+        start..end
+    }
 
-        let draw_start = std::cmp::min(std::cmp::max(start, 0), self.height as i32) as usize;
-        let draw_end = std::cmp::min(std::cmp::max(end, 0), self.height as i32) as usize;
+    /// Draws the wall between its bottom and top height. The ray carries on
+    /// above and below it.
+    fn render_wall(&mut self, x: usize, wall_hit: &WallHit, camera: &Camera, map: &Map) -> usize {
+        let (start, end) = self.wall_rows(wall_hit.length, wall_hit.bottom, wall_hit.top, camera);
 
+        let brightness = wall_hit.light * self.lighting.wall_shade(wall_hit.normal);
+        let normal = Vector3::new(wall_hit.normal.x, wall_hit.normal.y, 0.0);
+        // The lights are placed in the camera's map, so they only light the
+        // walls of that one.
+        let lit = std::ptr::eq(wall_hit.map, map);
+        // The reflection is drawn behind the wall, so the wall only covers
+        // the part that is not reflected.
+        let coverage = match wall_hit.color {
+            Color::Reflective { reflectivity, .. } => 1.0 - reflectivity,
+            _ => 1.0,
+        };
+        let sampler = Sampler {
+            filter: self.filter,
+            time: self.time,
+            footprint: 1.0 / (end - start) as f64,
+        };
         let mut drawn = 0;
-        for y in draw_start..draw_end {
+        for y in self.screen_rows((start, end)) {
             if !self.pixel_finished(x, y) {
-                let color = wall_hit.color.sample(Vector2 {
-                    x: wall_hit.x,
-                    y: ((y as i32 - start) as f64) / ((end - start) as f64),
+                let v = (self.planar_row(y as f64) - start as f64) / ((end - start) as f64);
+                let color = wall_hit.color.sample(
+                    Vector2 {
+                        x: wall_hit.x,
+                        y: v,
+                    },
+                    &sampler,
+                );
+                let z = wall_hit.top - v * (wall_hit.top - wall_hit.bottom);
+                let baked = wall_hit.lightmap.map_or([0.0; 3], |lightmap| {
+                    lightmap.wall(wall_hit.side, Vector2::new(wall_hit.x, v))
                 });
+                let base = baked.map(|light| light + brightness);
+                let light = if lit {
+                    self.lighting.light_at(map, base, (wall_hit.pos, z), normal)
+                } else {
+                    base
+                };
+                let mut color = self.lighting.apply(color, light, wall_hit.length);
+                color[3] *= coverage;
                 if self.set_pixel(x, y, color) {
                     drawn += 1;
                 }
@@ -115,19 +441,53 @@ impl Renderer {
         drawn
     }
 
-    fn render_floor(&mut self, x: usize, floor_hit: &FloorHit, camera: &Camera) -> usize {
-        let z = -camera.z() * 2.0 + 1.0 + floor_hit.floor_height * 2.0;
-        let start = self.y_from_floor_dist(floor_hit.dist2, z);
-        let end = self.y_from_floor_dist(floor_hit.dist1, z);
+    fn render_floor(
+        &mut self,
+        x: usize,
+        floor_hit: &FloorHit,
+        camera: &Camera,
+        map: &Map,
+    ) -> usize {
         let h = self.height as f64;
+        let (height, rise) = along_ray(floor_hit, floor_hit.floor_height1, floor_hit.floor_height2);
+        // The floor at distance `d` is drawn at `y = horizon + k / d - shift`.
+        // Flat floors are worked out the way they were before slopes, so they
+        // are drawn exactly the same.
+        let k = if rise == 0.0 {
+            let z = -camera.z() * 2.0 + 1.0 + height * 2.0;
+            h * (1.0 - z) / 2.0
+        } else {
+            (camera.z() - height) * h
+        };
+        let shift = rise * h;
+        let start = self.y_from_floor_dist(floor_hit.dist2, k, shift);
+        let lit = std::ptr::eq(floor_hit.map, map);
+        let end = self.y_from_floor_dist(floor_hit.dist1, k, shift);
+        let scale = floor_hit.world_scale();
 
         let mut drawn: usize = 0;
         for y in start..end {
-            let current_dist = h * (1.0 - z) / (2.0 * (y as f64) - h);
+            let current_dist = k / (self.planar_row(y as f64) - self.horizon + shift);
             if !self.pixel_finished(x, y) {
                 let weight = (current_dist - floor_hit.dist1) / (floor_hit.dist2 - floor_hit.dist1);
                 let floor_pos = weight * floor_hit.pos2 + (1.0 - weight) * floor_hit.pos1;
-                let color = floor_hit.floor_color.sample(floor_pos);
+                let sampler = self.floor_sampler(current_dist, scale, k);
+                let color = floor_hit.floor_color.sample(floor_pos, &sampler);
+                let point = (
+                    floor_hit.point_at(current_dist),
+                    height + rise * current_dist,
+                );
+                let baked = floor_hit
+                    .lightmap
+                    .map_or([0.0; 3], |lightmap| lightmap.floor(floor_pos));
+                let base = baked.map(|light| light + floor_hit.light);
+                let light = if lit {
+                    let normal = Vector3::new(0.0, 0.0, 1.0);
+                    self.lighting.light_at(map, base, point, normal)
+                } else {
+                    base
+                };
+                let color = self.lighting.apply(color, light, current_dist);
                 if self.set_pixel(x, y, color) {
                     drawn += 1;
                 }
@@ -136,19 +496,56 @@ impl Renderer {
         drawn
     }
 
-    fn render_ceiling(&mut self, x: usize, floor_hit: &FloorHit, camera: &Camera) -> usize {
-        let z = -camera.z() * 2.0 - 1.0 + floor_hit.ceiling_height * 2.0;
-        let start = self.y_from_ceiling_dist(floor_hit.dist1, z);
-        let end = self.y_from_ceiling_dist(floor_hit.dist2, z);
+    fn render_ceiling(
+        &mut self,
+        x: usize,
+        floor_hit: &FloorHit,
+        camera: &Camera,
+        map: &Map,
+    ) -> usize {
         let h = self.height as f64;
+        let (height, rise) = along_ray(
+            floor_hit,
+            floor_hit.ceiling_height1,
+            floor_hit.ceiling_height2,
+        );
+        // The ceiling at distance `d` is drawn at `y = horizon - k / d - shift`.
+        // Flat ceilings are worked out like flat floors.
+        let k = if rise == 0.0 {
+            let z = -camera.z() * 2.0 - 1.0 + height * 2.0;
+            h * (z + 1.0) / 2.0
+        } else {
+            (height - camera.z()) * h
+        };
+        let shift = rise * h;
+        let start = self.y_from_ceiling_dist(floor_hit.dist1, k, shift);
+        let lit = std::ptr::eq(floor_hit.map, map);
+        let end = self.y_from_ceiling_dist(floor_hit.dist2, k, shift);
+        let scale = floor_hit.world_scale();
 
         let mut drawn = 0;
         for y in start..end {
-            let current_dist = h * (z + 1.0) / (h - 2.0 * (y as f64));
+            let current_dist = k / (self.horizon - self.planar_row(y as f64) - shift);
             if !self.pixel_finished(x, y) {
                 let weight = (current_dist - floor_hit.dist1) / (floor_hit.dist2 - floor_hit.dist1);
                 let floor_pos = weight * floor_hit.pos2 + (1.0 - weight) * floor_hit.pos1;
-                let color = floor_hit.ceiling_color.sample(floor_pos);
+                let sampler = self.floor_sampler(current_dist, scale, k);
+                let color = floor_hit.ceiling_color.sample(floor_pos, &sampler);
+                let point = (
+                    floor_hit.point_at(current_dist),
+                    height + rise * current_dist,
+                );
+                let baked = floor_hit
+                    .lightmap
+                    .map_or([0.0; 3], |lightmap| lightmap.ceiling(floor_pos));
+                let base = baked.map(|light| light + floor_hit.light);
+                let light = if lit {
+                    let normal = Vector3::new(0.0, 0.0, -1.0);
+                    self.lighting.light_at(map, base, point, normal)
+                } else {
+                    base
+                };
+                let color = self.lighting.apply(color, light, current_dist);
                 if self.set_pixel(x, y, color) {
                     drawn += 1;
                 }
@@ -157,25 +554,118 @@ impl Renderer {
         drawn
     }
 
-    fn y_from_floor_dist(&self, dist: f64, z: f64) -> usize {
-        if dist == 0.0 {
+    fn render_sprite(&mut self, x: usize, sprite: &ProjectedSprite) -> usize {
+        let column = x as f64 + 0.5;
+        if column < sprite.start_x || column >= sprite.end_x {
+            return 0;
+        }
+        let tex_x = (column - sprite.start_x) / (sprite.end_x - sprite.start_x);
+
+        let draw_start = self.screen_row(sprite.start_y).max(0.0) as usize;
+        let draw_end = self.screen_row(sprite.end_y).clamp(0.0, self.height as f64) as usize;
+
+        let sampler = Sampler {
+            filter: self.filter,
+            time: self.time,
+            footprint: 1.0 / (sprite.end_y - sprite.start_y),
+        };
+        let mut drawn = 0;
+        for y in draw_start..draw_end {
+            if !self.pixel_finished(x, y) {
+                let color = sprite.color.sample(
+                    Vector2 {
+                        x: tex_x,
+                        y: (self.planar_row(y as f64 + 0.5) - sprite.start_y)
+                            / (sprite.end_y - sprite.start_y),
+                    },
+                    &sampler,
+                );
+                let color = self.lighting.apply(color, [sprite.light; 3], sprite.depth);
+                if self.set_pixel(x, y, color) {
+                    drawn += 1;
+                }
+            }
+        }
+        drawn
+    }
+
+    /// A floor or ceiling plane seen at `dist`, where `dist = k / (y - horizon)`
+    /// for screen row `y`. A pixel covers the larger of its extent along the
+    /// ray and across it.
+    fn floor_sampler(&self, dist: f64, scale: f64, k: f64) -> Sampler {
+        let along = dist * dist / k.abs() * scale;
+        let across = dist / self.height as f64;
+        Sampler {
+            filter: self.filter,
+            time: self.time,
+            footprint: along.max(across),
+        }
+    }
+
+    /// Returns where the planar projection would put screen row `y`. Walls,
+    /// floors and sprites are projected as if onto a plane, and the fisheye
+    /// projection spaces the rows of the plane evenly by angle.
+    fn planar_row(&self, y: f64) -> f64 {
+        match self.projection {
+            Projection::Planar | Projection::Cylindrical => y,
+            Projection::Fisheye => {
+                let h = self.height as f64;
+                // Rows more than a quarter turn away from the horizon see
+                // nothing of the plane.
+                let angle = ((y - self.horizon) / h).clamp(-1.57, 1.57);
+                self.horizon + h * angle.tan()
+            }
+        }
+    }
+
+    /// The inverse of `planar_row`.
+    fn screen_row(&self, y: f64) -> f64 {
+        match self.projection {
+            Projection::Planar | Projection::Cylindrical => y,
+            Projection::Fisheye => {
+                let h = self.height as f64;
+                self.horizon + h * ((y - self.horizon) / h).atan()
+            }
+        }
+    }
+
+    fn y_from_floor_dist(&self, dist: f64, k: f64, shift: f64) -> usize {
+        if k <= 0.0 {
+            // The camera is below the plane of the floor, so it sees nothing
+            // but its underside.
+            self.horizon.clamp(0.0, self.height as f64) as usize
+        } else if dist == 0.0 {
             self.height
         } else {
             std::cmp::min(
-                ((self.height as f64 * (dist - z + 1.0)) / (2.0 * dist)) as usize,
+                self.screen_row(self.horizon + k / dist - shift) as usize,
                 self.height,
             )
         }
     }
 
-    fn y_from_ceiling_dist(&self, dist: f64, z: f64) -> usize {
-        if dist == 0.0 {
+    fn y_from_ceiling_dist(&self, dist: f64, k: f64, shift: f64) -> usize {
+        if k <= 0.0 {
+            // The camera is above the plane of the ceiling, so it sees nothing
+            // but its top.
+            self.horizon.clamp(0.0, self.height as f64) as usize
+        } else if dist == 0.0 {
             0
         } else {
-            std::cmp::min(
-                (self.height as f64 * (dist - z - 1.0) / (2.0 * dist)) as usize,
-                self.height / 2,
-            )
+            let y = self.screen_row(self.horizon - k / dist - shift);
+            y.clamp(0.0, self.height as f64) as usize
         }
     }
 }
+
+/// Returns the height at distance 0 and the rise per unit of distance of a
+/// floor or ceiling plane along the ray, given its heights at both ends of
+/// `floor_hit`.
+fn along_ray(floor_hit: &FloorHit, height1: f64, height2: f64) -> (f64, f64) {
+    let rise = if floor_hit.dist2 > floor_hit.dist1 {
+        (height2 - height1) / (floor_hit.dist2 - floor_hit.dist1)
+    } else {
+        0.0
+    };
+    (height1 - rise * floor_hit.dist1, rise)
+}
@@ -1,11 +1,32 @@
 use cgmath::{Matrix2, Vector2};
 
+const MAX_PITCH: f64 = 1.0;
+
+/// How the view around the camera is laid out on the screen.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Projection {
+    /// A flat projection plane. Straight lines stay straight, but wide views
+    /// stretch towards the edges and the field of view has to stay below 180
+    /// degrees. Distances are measured from the plane.
+    Planar,
+    /// Columns spaced evenly by angle around the camera, like a panorama, for
+    /// fields of view up to 360 degrees. Vertical lines stay straight and
+    /// horizontal ones bend. Distances are measured from the camera.
+    Cylindrical,
+    /// Like `Cylindrical`, with the rows spaced evenly by angle as well, so
+    /// that lines bow out from the center like through a fisheye lens.
+    Fisheye,
+}
+
 pub struct Camera {
     pos: Vector2<f64>,
     dir_front: Vector2<f64>,
     dir_right: Vector2<f64>,
     plane: Vector2<f64>,
+    fov: f64,
+    projection: Projection,
     z: f64,
+    pitch: f64,
 }
 
 impl Camera {
@@ -13,9 +34,12 @@ impl Camera {
         Self {
             pos,
             dir_front: Vector2::new(rot.cos(), rot.sin()),
-            dir_right: Vector2::new(rot.sin(), rot.cos()),
-            plane: Vector2::new(rot.sin(), rot.cos()) * (fov / 2.0).tan(),
+            dir_right: Vector2::new(-rot.sin(), rot.cos()),
+            plane: Vector2::new(-rot.sin(), rot.cos()) * (fov / 2.0).tan(),
+            fov,
+            projection: Projection::Planar,
             z: 0.0,
+            pitch: 0.0,
         }
     }
 
@@ -32,16 +56,33 @@ impl Camera {
         self.pos += delta.x * self.dir_right + delta.y * self.dir_front;
     }
 
+    pub fn set_pos(&mut self, pos: Vector2<f64>) {
+        self.pos = pos;
+    }
+
+    pub fn set_projection(&mut self, projection: Projection) {
+        self.projection = projection;
+    }
+
     pub fn translate_z(&mut self, delta: f64) {
         self.z += delta;
-        println!("{}", self.z);
     }
 
-    pub fn rays(&self, width: u32) -> RayIterator {
+    /// Looks up or down by shifting the horizon. The pitch is measured in
+    /// screen heights, so a pitch of 0.5 puts the horizon at the bottom edge.
+    pub fn look(&mut self, delta: f64) {
+        self.pitch = (self.pitch + delta).clamp(-MAX_PITCH, MAX_PITCH);
+    }
+
+    /// Returns the direction of the ray for every column of a screen `width`
+    /// pixels wide. Hits along a ray are as far away as the length of the
+    /// ray times their distance, so the planar projection uses longer rays
+    /// towards the edges to keep distances measured from its plane.
+    pub fn rays(&self, width: u32) -> RayIterator<'_> {
         RayIterator {
             current_x: 0,
             width,
-            camera: &self,
+            camera: self,
         }
     }
 
@@ -49,9 +90,30 @@ impl Camera {
         self.pos
     }
 
+    pub fn dir(&self) -> Vector2<f64> {
+        self.dir_front
+    }
+
+    pub fn plane(&self) -> Vector2<f64> {
+        self.plane
+    }
+
+    /// Horizontal field of view in radians.
+    pub fn fov(&self) -> f64 {
+        self.fov
+    }
+
+    pub fn projection(&self) -> Projection {
+        self.projection
+    }
+
     pub fn z(&self) -> f64 {
         self.z
     }
+
+    pub fn pitch(&self) -> f64 {
+        self.pitch
+    }
 }
 
 pub struct RayIterator<'a> {
@@ -65,7 +127,14 @@ impl Iterator for RayIterator<'_> {
     fn next(&mut self) -> Option<Self::Item> {
         if self.current_x < self.width {
             let camera_x = 2.0 * (self.current_x as f64) / (self.width as f64) - 1.0;
-            let ray_dir = self.camera.dir_front + self.camera.plane * camera_x;
+            let camera = self.camera;
+            let ray_dir = match camera.projection {
+                Projection::Planar => camera.dir_front + camera.plane * camera_x,
+                Projection::Cylindrical | Projection::Fisheye => {
+                    let (sin, cos) = (camera_x * camera.fov / 2.0).sin_cos();
+                    camera.dir_front * cos + camera.dir_right * sin
+                }
+            };
             self.current_x += 1;
             Some(ray_dir)
         } else {
@@ -73,3 +142,33 @@ impl Iterator for RayIterator<'_> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use cgmath::InnerSpace;
+
+    use super::*;
+
+    #[test]
+    fn plane_is_perpendicular() {
+        for degrees in [0.0, 30.0, 90.0, 135.0, 200.0, -90.0] {
+            let camera = Camera::new(Vector2::new(0.0, 0.0), f64::to_radians(degrees), 1.0);
+            assert!(camera.dir().dot(camera.plane()).abs() < 1e-12);
+        }
+    }
+
+    #[test]
+    fn left_and_right() {
+        // Facing towards -y, which is up on the screen, so the first column
+        // looks towards -x and moving right goes towards +x.
+        let mut camera = Camera::new(
+            Vector2::new(0.0, 0.0),
+            f64::to_radians(-90.0),
+            f64::to_radians(90.0),
+        );
+        let rays: Vec<_> = camera.rays(2).collect();
+        assert!(rays[0].x < -0.5);
+        camera.translate(Vector2::new(1.0, 0.0));
+        assert!((camera.pos() - Vector2::new(1.0, 0.0)).magnitude() < 1e-12);
+    }
+}
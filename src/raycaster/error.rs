@@ -0,0 +1,121 @@
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+
+use super::ParseError;
+
+#[derive(Debug)]
+pub enum Error {
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    Image {
+        path: PathBuf,
+        source: image::ImageError,
+    },
+    Parse(ParseError),
+    WrongNumberOfColors {
+        sides: u32,
+        colors: usize,
+    },
+    OutOfBounds {
+        x: usize,
+        y: usize,
+        width: usize,
+        height: usize,
+    },
+    LightmapSize {
+        path: PathBuf,
+        width: usize,
+        height: usize,
+    },
+    LightmapOutdated {
+        path: PathBuf,
+    },
+    NotADoor {
+        x: usize,
+        y: usize,
+    },
+    NotAPortal {
+        x: usize,
+        y: usize,
+    },
+    UnsavedPortalTarget {
+        x: usize,
+        y: usize,
+    },
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::Image { path, source } => write!(f, "{}: {}", path.display(), source),
+            Self::Parse(err) => write!(f, "invalid map file: {}", err),
+            Self::WrongNumberOfColors { sides, colors } => write!(
+                f,
+                "wrong number of colors: the shape has {} sides but {} colors were given",
+                sides, colors
+            ),
+            Self::OutOfBounds {
+                x,
+                y,
+                width,
+                height,
+            } => write!(
+                f,
+                "tile ({}, {}) is outside the {}x{} map",
+                x, y, width, height
+            ),
+            Self::LightmapSize {
+                path,
+                width,
+                height,
+            } => write!(
+                f,
+                "the {}x{} lightmap {} does not fit the map",
+                width,
+                height,
+                path.display()
+            ),
+            Self::LightmapOutdated { path } => write!(
+                f,
+                "the lightmap {} was baked before the lights or tiles of the map changed",
+                path.display()
+            ),
+            Self::NotADoor { x, y } => write!(f, "tile ({}, {}) is not a door", x, y),
+            Self::NotAPortal { x, y } => write!(f, "tile ({}, {}) is not a portal", x, y),
+            Self::UnsavedPortalTarget { x, y } => write!(
+                f,
+                "the portal at ({}, {}) links to a map that is not in a file",
+                x, y
+            ),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Io { source, .. } => Some(source),
+            Self::Image { source, .. } => Some(source),
+            Self::Parse(err) => Some(err),
+            Self::WrongNumberOfColors { .. }
+            | Self::OutOfBounds { .. }
+            | Self::LightmapSize { .. }
+            | Self::LightmapOutdated { .. }
+            | Self::NotADoor { .. }
+            | Self::NotAPortal { .. }
+            | Self::UnsavedPortalTarget { .. } => None,
+        }
+    }
+}
+
+impl From<ParseError> for Error {
+    fn from(err: ParseError) -> Self {
+        Self::Parse(err)
+    }
+}
@@ -0,0 +1,39 @@
+use std::path::Path;
+
+use image::RgbaImage;
+
+use super::*;
+
+impl Renderer {
+    /// Renders a frame into an image instead of a window surface.
+    pub fn render_image(
+        &mut self,
+        camera: &Camera,
+        map: &Map,
+        sprites: &[Sprite],
+        time: f64,
+    ) -> RgbaImage {
+        let mut screen = vec![0; self.width * self.height * 4];
+        self.render(&mut screen, camera, map, sprites, time);
+        RgbaImage::from_raw(self.width as u32, self.height as u32, screen).unwrap()
+    }
+
+    /// Renders a frame and writes it to `path`. The format is picked from the
+    /// file extension.
+    pub fn render_to_file<P: AsRef<Path>>(
+        &mut self,
+        path: P,
+        camera: &Camera,
+        map: &Map,
+        sprites: &[Sprite],
+        time: f64,
+    ) -> Result<()> {
+        let path = path.as_ref();
+        self.render_image(camera, map, sprites, time)
+            .save(path)
+            .map_err(|source| Error::Image {
+                path: path.to_owned(),
+                source,
+            })
+    }
+}
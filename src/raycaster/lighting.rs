@@ -0,0 +1,152 @@
+use cgmath::{InnerSpace, Vector2, Vector3};
+
+use super::Map;
+
+#[derive(Clone, Copy)]
+pub enum Falloff {
+    None,
+    /// Fully clear before `start` and fully fogged after `end`.
+    Linear {
+        start: f64,
+        end: f64,
+    },
+    Exponential {
+        density: f64,
+    },
+    ExponentialSquared {
+        density: f64,
+    },
+}
+
+impl Falloff {
+    /// Returns how much of a sample at `dist` is visible through the fog.
+    pub fn visibility(&self, dist: f64) -> f64 {
+        match *self {
+            Self::None => 1.0,
+            Self::Linear { start, end } => ((end - dist) / (end - start)).clamp(0.0, 1.0),
+            Self::Exponential { density } => (-density * dist).exp(),
+            Self::ExponentialSquared { density } => (-(density * dist).powi(2)).exp(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Fog {
+    pub color: [f64; 3],
+    pub falloff: Falloff,
+}
+
+/// A light shining in every direction from a point, like a torch.
+#[derive(Clone, Copy)]
+pub struct PointLight {
+    pub pos: Vector2<f64>,
+    /// Height of the light.
+    pub z: f64,
+    pub color: [f64; 3],
+    /// Distance at which the light has faded out completely.
+    pub radius: f64,
+    pub intensity: f64,
+}
+
+impl PointLight {
+    /// Returns the light falling on a surface at `pos` and height `z` that
+    /// faces along the unit vector `normal`. With `shadows`, walls in `map`
+    /// block the light.
+    pub fn shine(
+        &self,
+        map: &Map,
+        (pos, z): (Vector2<f64>, f64),
+        normal: Vector3<f64>,
+        shadows: bool,
+    ) -> [f64; 3] {
+        let to_light = Vector3::new(self.pos.x - pos.x, self.pos.y - pos.y, self.z - z);
+        let dist = to_light.magnitude();
+        if dist >= self.radius {
+            return [0.0; 3];
+        }
+        let facing = if dist > 0.0 {
+            normal.dot(to_light) / dist
+        } else {
+            1.0
+        };
+        if facing <= 0.0 {
+            return [0.0; 3];
+        }
+        if shadows {
+            // Start just in front of the surface, far enough for the hit
+            // tolerance of the shapes to not find it.
+            let offset = normal * (0.002 * dist);
+            let from = (pos + offset.truncate(), z + offset.z);
+            if !map.line_of_sight(from, (self.pos, self.z)) {
+                return [0.0; 3];
+            }
+        }
+        let strength = self.intensity * facing * (1.0 - dist / self.radius).powi(2);
+        self.color.map(|c| c * strength)
+    }
+}
+
+#[derive(Clone)]
+pub struct Lighting {
+    pub fog: Fog,
+    /// Brightness of walls facing along the y axis, relative to walls facing
+    /// along the x axis.
+    pub side_shade: f64,
+    /// Lights added to the light levels of the tiles on walls, floors and
+    /// ceilings. Sprites only get the light level of their tile.
+    pub lights: Vec<PointLight>,
+    /// Whether walls block the point lights. This casts a ray to every light
+    /// in reach of a pixel. Floors and ceilings let the light through, so a
+    /// light on a layer also lights the room below it.
+    pub shadows: bool,
+}
+
+impl Default for Lighting {
+    fn default() -> Self {
+        Self {
+            fog: Fog {
+                color: [0.0; 3],
+                falloff: Falloff::None,
+            },
+            side_shade: 1.0,
+            lights: Vec::new(),
+            shadows: false,
+        }
+    }
+}
+
+impl Lighting {
+    pub fn apply(&self, color: [f64; 4], light: [f64; 3], dist: f64) -> [f64; 4] {
+        let visibility = self.fog.falloff.visibility(dist);
+        let mut lit = color;
+        for i in 0..3 {
+            lit[i] = color[i] * light[i] * visibility + self.fog.color[i] * (1.0 - visibility);
+        }
+        lit
+    }
+
+    /// Returns the light falling on a surface that faces along the unit
+    /// vector `normal`, for a tile with the light `base`. `point` is where
+    /// the surface is in `map` and how high.
+    pub fn light_at(
+        &self,
+        map: &Map,
+        base: [f64; 3],
+        point: (Vector2<f64>, f64),
+        normal: Vector3<f64>,
+    ) -> [f64; 3] {
+        let mut total = base;
+        for light in &self.lights {
+            let light = light.shine(map, point, normal, self.shadows);
+            for (total, light) in total.iter_mut().zip(light) {
+                *total += light;
+            }
+        }
+        total
+    }
+
+    /// Brightness of a wall with the given unit normal.
+    pub fn wall_shade(&self, normal: Vector2<f64>) -> f64 {
+        1.0 - (1.0 - self.side_shade) * normal.y * normal.y
+    }
+}
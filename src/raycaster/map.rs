@@ -1,4 +1,10 @@
-use cgmath::Vector2;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use cgmath::{InnerSpace, Matrix2, Vector2};
+
+use super::{Error, PointLight};
 
 pub mod shape;
 pub use shape::*;
@@ -6,11 +12,131 @@ pub use shape::*;
 pub mod color;
 pub use color::*;
 
+pub mod file;
+pub use file::*;
+
+pub mod collision;
+
+pub mod lightmap;
+pub use lightmap::*;
+
+/// How many portals and mirrors a single ray may pass through, which keeps two
+/// portals or mirrors that face each other from recursing forever.
+const MAX_RAY_DEPTH: usize = 8;
+
 pub struct Map {
     width: usize,
     height: usize,
     tiles: Vec<Tile>,
+    doors: HashMap<usize, DoorAnimation>,
+    portals: HashMap<usize, PortalLink>,
+    layers: Vec<Layer>,
+    /// The file the map was loaded from.
+    path: Option<PathBuf>,
     pub wall_height: f64,
+    /// Panorama drawn behind everything else. Its `x` coordinate wraps once
+    /// around the camera and `y` goes from straight up to straight down.
+    pub sky: Option<Color>,
+    /// Lights that never move. They only show up once baked into `lightmap`.
+    pub lights: Vec<PointLight>,
+    /// Light baked from `lights`, added to the light level of the tiles.
+    pub lightmap: Option<Lightmap>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DoorState {
+    Closed,
+    Open,
+}
+
+struct DoorAnimation {
+    open: f64,
+    target: DoorState,
+}
+
+/// A grid of tiles stacked above the bottom one, for rooms above rooms. The
+/// heights of its tiles are measured from the elevation of the layer, and
+/// collision treats a tile as solid from there up to its floor. Doors,
+/// portals and mirrors only work in the bottom grid, and map files that put
+/// them in a layer are rejected.
+pub struct Layer {
+    elevation: f64,
+    width: usize,
+    height: usize,
+    tiles: Vec<Option<Tile>>,
+}
+
+impl Layer {
+    pub fn elevation(&self) -> f64 {
+        self.elevation
+    }
+
+    pub fn set_tile(&mut self, x: usize, y: usize, tile: Option<Tile>) {
+        self.try_set_tile(x, y, tile)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    /// Places `tile` at (`x`, `y`), or leaves the place empty if it is `None`.
+    pub fn try_set_tile(&mut self, x: usize, y: usize, tile: Option<Tile>) -> super::Result<()> {
+        if x >= self.width || y >= self.height {
+            return Err(Error::OutOfBounds {
+                x,
+                y,
+                width: self.width,
+                height: self.height,
+            });
+        }
+        self.tiles[y * self.width + x] = tile;
+        Ok(())
+    }
+
+    pub fn get_tile(&self, x: i32, y: i32) -> Option<&Tile> {
+        self.tile(Vector2::new(x, y))
+    }
+
+    fn tile(&self, map_pos: Vector2<i32>) -> Option<&Tile> {
+        if map_pos.x < 0 || map_pos.x >= self.width as i32 {
+            return None;
+        }
+        if map_pos.y < 0 || map_pos.y >= self.height as i32 {
+            return None;
+        }
+        self.tiles[map_pos.y as usize * self.width + map_pos.x as usize].as_ref()
+    }
+}
+
+/// Where a portal leads to.
+#[derive(Clone)]
+pub struct PortalLink {
+    /// The tile the portal tile is placed over.
+    pub target: Vector2<usize>,
+    /// Rotation around the tile center, in radians.
+    pub rotation: f64,
+    /// The map the target is in, or `None` for the map the portal is in.
+    pub map: Option<Arc<Map>>,
+}
+
+impl PortalLink {
+    /// Moves a point given relative to the portal tile, and a direction,
+    /// through the portal.
+    pub fn transform(
+        &self,
+        tile_pos: Vector2<f64>,
+        dir: Vector2<f64>,
+    ) -> (Vector2<f64>, Vector2<f64>) {
+        let rot_mat = Matrix2::new(
+            self.rotation.cos(),
+            self.rotation.sin(),
+            -self.rotation.sin(),
+            self.rotation.cos(),
+        );
+        let center = Vector2::new(0.5, 0.5);
+        let target: Vector2<f64> = self.target.cast().unwrap();
+        (
+            rot_mat * (tile_pos - center) + center + target,
+            rot_mat * dir,
+        )
+    }
 }
 
 impl Map {
@@ -31,30 +157,170 @@ impl Map {
             width,
             height,
             tiles,
+            doors: HashMap::new(),
+            portals: HashMap::new(),
+            layers: Vec::new(),
+            path: None,
             wall_height,
+            sky: None,
+            lights: Vec::new(),
+            lightmap: None,
         }
     }
 
     pub fn set_tile(&mut self, x: usize, y: usize, tile: Tile) {
-        if x >= self.width {
-            panic!("x: {} is outside the range [0, {})", x, self.width);
+        self.try_set_tile(x, y, tile)
+            .unwrap_or_else(|err| panic!("{}", err));
+    }
+
+    pub fn try_set_tile(&mut self, x: usize, y: usize, tile: Tile) -> super::Result<()> {
+        let index = self.tile_index(x, y)?;
+        self.tiles[index] = tile;
+        self.doors.remove(&index);
+        self.portals.remove(&index);
+        Ok(())
+    }
+
+    pub fn get_tile(&self, x: i32, y: i32) -> Option<Tile> {
+        self.tile(Vector2::new(x, y)).cloned()
+    }
+
+    /// Adds an empty layer at `elevation` above the bottom of the map.
+    pub fn add_layer(&mut self, elevation: f64) -> &mut Layer {
+        let index = self
+            .layers
+            .iter()
+            .take_while(|layer| layer.elevation <= elevation)
+            .count();
+        self.layers.insert(
+            index,
+            Layer {
+                elevation,
+                width: self.width,
+                height: self.height,
+                tiles: vec![None; self.width * self.height],
+            },
+        );
+        &mut self.layers[index]
+    }
+
+    /// Returns the layers stacked on top of the map, from the bottom up.
+    pub fn layers(&self) -> &[Layer] {
+        &self.layers
+    }
+
+    pub fn layers_mut(&mut self) -> &mut [Layer] {
+        &mut self.layers
+    }
+
+    /// Opens or closes the door at (`x`, `y`). Fails if the tile is outside
+    /// the map or has no door.
+    pub fn set_door_state(&mut self, x: usize, y: usize, state: DoorState) -> super::Result<()> {
+        let index = self.door_index(x, y)?;
+        self.doors
+            .entry(index)
+            .or_insert(DoorAnimation {
+                open: 0.0,
+                target: DoorState::Closed,
+            })
+            .target = state;
+        Ok(())
+    }
+
+    /// Returns the state the door at (`x`, `y`) is moving towards, or `None`
+    /// if there is no door.
+    pub fn door_state(&self, x: usize, y: usize) -> Option<DoorState> {
+        let index = self.door_index(x, y).ok()?;
+        Some(
+            self.doors
+                .get(&index)
+                .map_or(DoorState::Closed, |door| door.target),
+        )
+    }
+
+    /// Returns how far the door is open, from 0 (closed) to 1 (open), or
+    /// `None` if there is no door.
+    pub fn door_open(&self, x: usize, y: usize) -> Option<f64> {
+        let index = self.door_index(x, y).ok()?;
+        Some(self.doors.get(&index).map_or(0.0, |door| door.open))
+    }
+
+    /// Animates the doors towards their state.
+    pub fn update(&mut self, delta_time: f64) {
+        for (index, door) in self.doors.iter_mut() {
+            // Skip doors whose tile has been replaced in the meantime.
+            let Some(shape) = self.tiles[*index].shape.door() else {
+                continue;
+            };
+            let speed = shape.speed;
+            let step = speed * delta_time;
+            door.open = match door.target {
+                DoorState::Open => (door.open + step).min(1.0),
+                DoorState::Closed => (door.open - step).max(0.0),
+            };
         }
-        if y >= self.height {
-            panic!("y: {} is outside the range [0, {})", y, self.height);
+    }
+
+    /// Links the portal at (`x`, `y`) to another tile. Fails if the tile is
+    /// outside the map or not a portal, or if the target is outside the map
+    /// it is in.
+    pub fn set_portal_link(&mut self, x: usize, y: usize, link: PortalLink) -> super::Result<()> {
+        let index = self.tile_index(x, y)?;
+        if !matches!(self.tiles[index].shape, Shape::Portal(_)) {
+            return Err(Error::NotAPortal { x, y });
         }
+        let target_map = link.map.as_deref().unwrap_or(self);
+        target_map.tile_index(link.target.x, link.target.y)?;
+        self.portals.insert(index, link);
+        Ok(())
+    }
 
-        self.tiles[y * self.width + x] = tile;
+    /// Returns where the portal at (`x`, `y`) leads to, or `None` if it is not
+    /// linked.
+    pub fn portal_link(&self, x: usize, y: usize) -> Option<&PortalLink> {
+        self.portals.get(&self.tile_index(x, y).ok()?)
     }
 
-    pub fn get_tile(&self, x: i32, y: i32) -> Option<Tile> {
-        if x < 0 || x >= self.width as i32 {
-            return None;
+    /// Returns the file the map was loaded from.
+    pub fn path(&self) -> Option<&Path> {
+        self.path.as_deref()
+    }
+
+    fn door_index(&self, x: usize, y: usize) -> super::Result<usize> {
+        let index = self.tile_index(x, y)?;
+        if self.tiles[index].shape.door().is_none() {
+            return Err(Error::NotADoor { x, y });
         }
-        if y < 0 || y >= self.height as i32 {
-            return None;
+        Ok(index)
+    }
+
+    fn tile_index(&self, x: usize, y: usize) -> super::Result<usize> {
+        if x >= self.width || y >= self.height {
+            return Err(Error::OutOfBounds {
+                x,
+                y,
+                width: self.width,
+                height: self.height,
+            });
         }
+        Ok(y * self.width + x)
+    }
 
-        Some(self.tiles[y as usize * self.width + x as usize].clone())
+    /// Returns every hit in the tile from near to far. Portals are handled by
+    /// `portal_ray_cast`.
+    fn tile_ray_cast(
+        &self,
+        map_pos: Vector2<i32>,
+        tile: &Tile,
+        pos: Vector2<f64>,
+        dir: Vector2<f64>,
+    ) -> Vec<ShapeHitInfo> {
+        let index = map_pos.y as usize * self.width + map_pos.x as usize;
+        let open = self.doors.get(&index).map_or(0.0, |door| door.open);
+        let mut hits = Vec::new();
+        tile.shape.ray_cast_all(pos, dir, open, &mut hits);
+        hits.sort_by(|a, b| a.length.total_cmp(&b.length));
+        hits
     }
 
     pub fn width(&self) -> usize {
@@ -64,16 +330,385 @@ impl Map {
         self.height
     }
 
+    /// Continues the ray from the linked tile if it hits the front of a
+    /// portal in the tile at `map_pos`. `entry` is where the ray entered the
+    /// tile and how far that was. Returns `None` if the ray does not hit the
+    /// front of a linked portal, in which case it carries on in this map, and
+    /// otherwise whether `hit_callback` ended the ray.
+    fn portal_ray_cast(
+        &self,
+        (map_pos, tile): (Vector2<i32>, &Tile),
+        (entry, entry_dist): (Vector2<f64>, f64),
+        dir: Vector2<f64>,
+        (height, depth): (f64, usize),
+        hit_callback: &mut dyn FnMut(Hit) -> bool,
+    ) -> Option<bool> {
+        let portal = match &tile.shape {
+            Shape::Portal(portal) => portal,
+            _ => return None,
+        };
+        let index = map_pos.y as usize * self.width + map_pos.x as usize;
+        let link = self.portals.get(&index)?;
+        let info = portal.ray_cast(entry, dir)?;
+
+        let exit = (entry + dir * info.length, entry_dist + info.length);
+        if self.floor_hits(
+            (map_pos, tile),
+            (entry, entry_dist),
+            exit,
+            height,
+            hit_callback,
+        ) {
+            return Some(true);
+        }
+        let map = link.map.as_deref().unwrap_or(self);
+        let (pos, dir) = link.transform(exit.0, dir);
+        Some(redirect_ray(
+            exit.1,
+            (map, pos, dir),
+            (height, depth),
+            hit_callback,
+        ))
+    }
+
+    /// Reflects the ray if it hit a reflective side of `tile`. Returns `None`
+    /// if the ray carries on, and otherwise whether `hit_callback` ended it. A
+    /// mirror that does not span the full height of the walls leaves what is
+    /// above and below it to the rest of the ray.
+    fn reflect_ray(
+        &self,
+        (map_pos, tile): (Vector2<i32>, &Tile),
+        (entry, entry_dist): (Vector2<f64>, f64),
+        info: &ShapeHitInfo,
+        dir: Vector2<f64>,
+        (height, depth): (f64, usize),
+        hit_callback: &mut dyn FnMut(Hit) -> bool,
+    ) -> Option<bool> {
+        if !matches!(tile.colors[info.side as usize], Color::Reflective { .. }) {
+            return None;
+        }
+        let exit = (entry + dir * info.length, entry_dist + info.length);
+        let reflected = dir - 2.0 * dir.dot(info.normal) * info.normal;
+        // Start just in front of the surface, far enough for the hit
+        // tolerance of the shapes to not find it again.
+        let pos = exit.0 + map_pos.cast().unwrap() + info.normal * (0.002 * dir.magnitude());
+        let ray = (height, depth);
+        if !self.full_height(tile) {
+            return redirect_ray(exit.1, (self, pos, reflected), ray, hit_callback).then_some(true);
+        }
+        let base = (map_pos, tile);
+        Some(
+            self.floor_hits(base, (entry, entry_dist), exit, height, hit_callback)
+                || redirect_ray(exit.1, (self, pos, reflected), ray, hit_callback),
+        )
+    }
+
+    /// Whether the walls of `tile` span the full height of the map's walls.
+    fn full_height(&self, tile: &Tile) -> bool {
+        tile.wall_bottom <= 0.0 && tile.wall_top.is_none_or(|top| top >= self.wall_height)
+    }
+
+    /// Returns the hits on the walls in every layer at `map_pos` from near to
+    /// far, with the grid and tile they are in. A portal in the bottom grid
+    /// is hit like a wall.
+    fn tile_walls<'a>(
+        &'a self,
+        (map_pos, tile): (Vector2<i32>, &'a Tile),
+        pos: Vector2<f64>,
+        dir: Vector2<f64>,
+    ) -> Vec<(usize, &'a Tile, ShapeHitInfo)> {
+        let mut hits = Vec::new();
+        for (index, layer) in self.layers.iter().enumerate() {
+            if let Some(tile) = layer.tile(map_pos) {
+                let mut infos = Vec::new();
+                tile.shape.ray_cast_all(pos, dir, 0.0, &mut infos);
+                hits.extend(infos.into_iter().map(|info| (index + 1, tile, info)));
+            }
+        }
+        let infos = match &tile.shape {
+            Shape::Portal(portal) => portal.ray_cast(pos, dir).into_iter().collect(),
+            _ => self.tile_ray_cast(map_pos, tile, pos, dir),
+        };
+        hits.extend(infos.into_iter().map(|info| (0, tile, info)));
+        hits.sort_by(|a, b| a.2.length.total_cmp(&b.2.length));
+        hits
+    }
+
+    /// Reports the walls in every layer at `map_pos` from near to far, for a
+    /// ray that entered the tile at `entry`. Returns `None` if the ray carries
+    /// on past the tile, and otherwise whether `hit_callback` ended it.
+    fn wall_hits(
+        &self,
+        (map_pos, tile): (Vector2<i32>, &Tile),
+        entry: (Vector2<f64>, f64),
+        dir: Vector2<f64>,
+        ray: (f64, usize),
+        hit_callback: &mut dyn FnMut(Hit) -> bool,
+    ) -> Option<bool> {
+        for (grid, tile, info) in self.tile_walls((map_pos, tile), entry.0, dir) {
+            let base = (map_pos, tile);
+            if let (0, Shape::Portal(_)) = (grid, &tile.shape) {
+                // Unlinked portals are not seen at all.
+                if let Some(ended) = self.portal_ray_cast(base, entry, dir, ray, hit_callback) {
+                    return Some(ended);
+                }
+                continue;
+            }
+            let hit = self.wall_hit((grid, map_pos, tile), &info, entry, dir);
+            if hit_callback(Hit::WallHit(hit)) {
+                return Some(true);
+            }
+            if grid == 0 {
+                if let Some(ended) = self.reflect_ray(base, entry, &info, dir, ray, hit_callback) {
+                    return Some(ended);
+                }
+            }
+        }
+        None
+    }
+
+    /// Returns the hit on a wall of `tile` at `map_pos` in `grid`, which is 0
+    /// for the bottom grid and `i + 1` for layer `i`.
+    fn wall_hit<'a>(
+        &'a self,
+        (grid, map_pos, tile): (usize, Vector2<i32>, &'a Tile),
+        info: &ShapeHitInfo,
+        (entry, entry_dist): (Vector2<f64>, f64),
+        dir: Vector2<f64>,
+    ) -> WallHit<'a> {
+        let elevation = match grid {
+            0 => 0.0,
+            _ => self.layers[grid - 1].elevation,
+        };
+        WallHit {
+            map: self,
+            length: entry_dist + info.length,
+            pos: map_pos.cast().unwrap() + entry + dir * info.length,
+            x: info.x,
+            normal: info.normal,
+            dir,
+            side: info.side,
+            color: &tile.colors[info.side as usize],
+            light: tile.light,
+            lightmap: self.lightmap_tile(grid, map_pos),
+            bottom: elevation + tile.wall_bottom,
+            top: elevation + tile.wall_top.unwrap_or(self.wall_height),
+            redirect_dist: None,
+            redirects: 0,
+        }
+    }
+
+    fn lightmap_tile(&self, grid: usize, map_pos: Vector2<i32>) -> Option<LightmapTile<'_>> {
+        self.lightmap.as_ref()?.tile(grid, map_pos)
+    }
+
+    /// Reports the floors and ceilings in every layer at `map_pos` between
+    /// two points given relative to the tile with their distances. The layer
+    /// the ray is in at `height` comes first, then the ones above it and then
+    /// the ones below it, so each floor is reported before the ones it hides.
+    /// Returns `true` if the ray ends there.
+    fn floor_hits(
+        &self,
+        (map_pos, tile): (Vector2<i32>, &Tile),
+        from: (Vector2<f64>, f64),
+        to: (Vector2<f64>, f64),
+        height: f64,
+        hit_callback: &mut dyn FnMut(Hit) -> bool,
+    ) -> bool {
+        let first = self
+            .layers
+            .iter()
+            .take_while(|layer| layer.elevation <= height)
+            .count();
+        for index in (first..=self.layers.len()).chain((0..first).rev()) {
+            let lightmap = self.lightmap_tile(index, map_pos);
+            let hit = match index {
+                0 => floor_hit(self, (map_pos, tile), 0.0, lightmap, from, to),
+                _ => {
+                    let layer = &self.layers[index - 1];
+                    match layer.tile(map_pos) {
+                        Some(tile) => {
+                            let elevation = layer.elevation;
+                            floor_hit(self, (map_pos, tile), elevation, lightmap, from, to)
+                        }
+                        None => continue,
+                    }
+                }
+            };
+            if hit_callback(Hit::FloorHit(hit)) {
+                return true;
+            }
+        }
+        false
+    }
+
     pub fn ray_cast(
         &self,
         pos: Vector2<f64>,
         dir: Vector2<f64>,
         hit_callback: &mut dyn FnMut(Hit) -> bool,
     ) {
-        let mut map_pos: Vector2<i32> = pos.cast().unwrap();
+        self.ray_cast_at_height(pos, 0.0, dir, hit_callback);
+    }
+
+    /// Like `ray_cast`, for a ray at `height`. This decides the order in which
+    /// the floors and ceilings of the layers are reported.
+    pub fn ray_cast_at_height(
+        &self,
+        pos: Vector2<f64>,
+        height: f64,
+        dir: Vector2<f64>,
+        hit_callback: &mut dyn FnMut(Hit) -> bool,
+    ) {
+        self.ray_cast_from(pos, dir, (height, 0), hit_callback);
+    }
+
+    /// Returns whether no wall is in the way between two points given with
+    /// their heights. Nothing is seen through portals and mirrors: linked
+    /// portals block the view like walls and mirrors are walls like any
+    /// other. Floors and ceilings never block the view.
+    pub fn line_of_sight(
+        &self,
+        (from, from_z): (Vector2<f64>, f64),
+        (to, to_z): (Vector2<f64>, f64),
+    ) -> bool {
+        let dir = to - from;
+        if dir.magnitude2() == 0.0 {
+            return true;
+        }
+        // With `dir` reaching from one point to the other, the second point
+        // is at distance 1.
+        let mut walk = GridWalk::new(from, dir);
+        let mut dist = 0.0;
+        while dist < 1.0 {
+            let map_pos = walk.map_pos;
+            let tile = match self.tile(map_pos) {
+                Some(tile) => tile,
+                None => return true,
+            };
+            let entry = (from + dir * dist - map_pos.cast().unwrap(), dist);
+            for (grid, tile, info) in self.tile_walls((map_pos, tile), entry.0, dir) {
+                if entry.1 + info.length >= 1.0 {
+                    break;
+                }
+                if let Shape::Portal(_) = tile.shape {
+                    let index = map_pos.y as usize * self.width + map_pos.x as usize;
+                    if self.portals.contains_key(&index) {
+                        return false;
+                    }
+                    continue;
+                }
+                let hit = self.wall_hit((grid, map_pos, tile), &info, entry, dir);
+                let z = from_z + (to_z - from_z) * hit.length;
+                if z >= hit.bottom && z <= hit.top {
+                    return false;
+                }
+            }
+            dist = walk.next_tile();
+        }
+        true
+    }
+
+    /// Ray casts at `height` after the ray went through `depth` portals or
+    /// mirrors. Returns whether `hit_callback` ended the ray.
+    fn ray_cast_from(
+        &self,
+        pos: Vector2<f64>,
+        dir: Vector2<f64>,
+        (height, depth): (f64, usize),
+        hit_callback: &mut dyn FnMut(Hit) -> bool,
+    ) -> bool {
+        let mut walk = GridWalk::new(pos, dir);
+        let ray = (height, depth);
+
+        let tile = match self.tile(walk.map_pos) {
+            Some(tile) => tile,
+            None => return false,
+        };
+        let entry = (pos - walk.map_pos.cast().unwrap(), 0.0);
+        if let Some(ended) = self.wall_hits((walk.map_pos, tile), entry, dir, ray, hit_callback) {
+            return ended;
+        }
+
+        let mut last_pos = pos;
+        let mut last_dist = 0.0;
+
+        loop {
+            let last_map_pos = walk.map_pos;
+            let dist = walk.next_tile();
+            let map_pos = walk.map_pos;
+            let tile_pos = pos + dir * dist;
+
+            let tile = match self.tile(last_map_pos) {
+                Some(tile) => tile,
+                None => return false,
+            };
+            let offset: Vector2<f64> = last_map_pos.cast().unwrap();
+            let from = (last_pos - offset, last_dist);
+            let to = (tile_pos - offset, dist);
+            if self.floor_hits((last_map_pos, tile), from, to, height, hit_callback) {
+                return true;
+            }
+            last_pos = tile_pos;
+            last_dist = dist;
+
+            if let Some(tile) = self.tile(map_pos) {
+                let entry = (tile_pos - map_pos.cast().unwrap(), dist);
+                if let Some(ended) = self.wall_hits((map_pos, tile), entry, dir, ray, hit_callback)
+                {
+                    return ended;
+                }
+            }
+        }
+    }
+
+    fn tile(&self, map_pos: Vector2<i32>) -> Option<&Tile> {
+        if map_pos.x < 0 || map_pos.x >= self.width as i32 {
+            return None;
+        }
+        if map_pos.y < 0 || map_pos.y >= self.height as i32 {
+            return None;
+        }
+        Some(&self.tiles[map_pos.y as usize * self.width + map_pos.x as usize])
+    }
+}
+
+/// Continues a ray that reached a portal or mirror at `dist` from `pos` in
+/// `map` in the direction `dir`. Nothing is seen through the portal or mirror
+/// once the ray went through too many of them. Returns whether `hit_callback`
+/// ended the ray.
+fn redirect_ray(
+    dist: f64,
+    (map, pos, dir): (&Map, Vector2<f64>, Vector2<f64>),
+    (height, depth): (f64, usize),
+    hit_callback: &mut dyn FnMut(Hit) -> bool,
+) -> bool {
+    if depth >= MAX_RAY_DEPTH {
+        return false;
+    }
+    map.ray_cast_from(pos, dir, (height, depth + 1), &mut |mut hit| {
+        hit.redirect(dist);
+        hit_callback(hit)
+    })
+}
+
+/// Walks a ray through the tiles of a grid in the order it crosses them.
+struct GridWalk {
+    /// The tile the ray is in.
+    map_pos: Vector2<i32>,
+    /// Distance along the ray to the next tile edge along each axis.
+    side_dist: Vector2<f64>,
+    /// Distance along the ray between the tile edges along each axis.
+    delta_dist: Vector2<f64>,
+    step: Vector2<i32>,
+}
+
+impl GridWalk {
+    fn new(pos: Vector2<f64>, dir: Vector2<f64>) -> Self {
+        let map_pos: Vector2<i32> = pos.cast().unwrap();
         let delta_dist = dir.map(|a| 1.0 / a.abs());
 
-        let mut side_dist = Vector2::new(
+        let side_dist = Vector2::new(
             if dir.x < 0.0 {
                 (pos.x - map_pos.x as f64) * delta_dist.x
             } else {
@@ -86,91 +721,58 @@ impl Map {
             },
         );
 
-        let step = dir.map(|a| if a < 0.0 { -1 } else { 1 });
-
-        let hit_tile = self.get_tile(map_pos.x, map_pos.y);
-        if let Some(tile) = hit_tile {
-            let tile_pos = pos - map_pos.cast().unwrap();
-            if let Some(shape_info) = tile.shape.ray_cast(tile_pos, dir) {
-                let hit_info = Hit::WallHit(WallHit {
-                    length: shape_info.length,
-                    x: shape_info.x,
-                    color: &tile.colors[shape_info.side as usize],
-                });
-                if hit_callback(hit_info) {
-                    return;
-                };
-            }
-        } else {
-            return;
+        Self {
+            map_pos,
+            side_dist,
+            delta_dist,
+            step: dir.map(|a| if a < 0.0 { -1 } else { 1 }),
         }
+    }
 
-        let hit = false;
-        let mut side;
-        let mut last_pos = pos;
-        let mut last_map_pos;
-        let mut dist;
-        let mut last_dist = 0.0;
-
-        while !hit {
-            let mut tile_pos = pos;
-            last_map_pos = map_pos;
-            if side_dist.x < side_dist.y {
-                dist = side_dist.x;
-                tile_pos += dir * side_dist.x;
-                side_dist.x += delta_dist.x;
-                map_pos.x += step.x;
-                side = 0;
-            } else {
-                dist = side_dist.y;
-                tile_pos += dir * side_dist.y;
-                side_dist.y += delta_dist.y;
-                map_pos.y += step.y;
-                side = 1;
-            }
-
-            let hit_tile = self.get_tile(last_map_pos.x, last_map_pos.y);
-            if let Some(tile) = hit_tile {
-                let floor_hit = FloorHit {
-                    pos1: last_pos - last_map_pos.cast().unwrap(),
-                    pos2: tile_pos - last_map_pos.cast().unwrap(),
-                    dist1: last_dist,
-                    dist2: dist,
-                    floor_color: &tile.floor_color,
-                    floor_height: tile.floor_height,
-                    ceiling_color: &tile.ceiling_color,
-                    ceiling_height: tile.ceiling_height,
-                };
-                if hit_callback(Hit::FloorHit(floor_hit)) {
-                    return;
-                }
-            } else {
-                return;
-            }
-            last_pos = tile_pos;
-            last_dist = dist;
-
-            tile_pos -= map_pos.cast().unwrap();
-            //println!("{:?}", map_pos);
-            let hit_tile = self.get_tile(map_pos.x, map_pos.y);
-            if let Some(tile) = hit_tile {
-                if let Some(shape_info) = tile.shape.ray_cast(tile_pos, dir) {
-                    let perp_wall_dist = if side == 0 {
-                        side_dist.x - delta_dist.x
-                    } else {
-                        side_dist.y - delta_dist.y
-                    };
-                    let hit_info = Hit::WallHit(WallHit {
-                        length: shape_info.length + perp_wall_dist,
-                        x: shape_info.x,
-                        color: &tile.colors[shape_info.side as usize],
-                    });
-                    if hit_callback(hit_info) {
-                        return;
-                    };
-                }
-            }
+    /// Moves on to the next tile and returns the distance at which the ray
+    /// enters it.
+    fn next_tile(&mut self) -> f64 {
+        let dist;
+        if self.side_dist.x < self.side_dist.y {
+            dist = self.side_dist.x;
+            self.side_dist.x += self.delta_dist.x;
+            self.map_pos.x += self.step.x;
+        } else {
+            dist = self.side_dist.y;
+            self.side_dist.y += self.delta_dist.y;
+            self.map_pos.y += self.step.y;
         }
+        dist
+    }
+}
+
+/// Returns the floor and ceiling of `tile` at `map_pos` in `map` between two
+/// points given relative to the tile with their distances.
+fn floor_hit<'a>(
+    map: &'a Map,
+    (map_pos, tile): (Vector2<i32>, &'a Tile),
+    elevation: f64,
+    lightmap: Option<LightmapTile<'a>>,
+    (pos1, dist1): (Vector2<f64>, f64),
+    (pos2, dist2): (Vector2<f64>, f64),
+) -> FloorHit<'a> {
+    FloorHit {
+        map,
+        map_pos,
+        pos1,
+        pos2,
+        dist1,
+        dist2,
+        floor_color: &tile.floor_color,
+        floor_height1: elevation + tile.floor_height_at(pos1),
+        floor_height2: elevation + tile.floor_height_at(pos2),
+        ceiling_color: &tile.ceiling_color,
+        ceiling_height1: elevation + tile.ceiling_height_at(pos1),
+        ceiling_height2: elevation + tile.ceiling_height_at(pos2),
+        light: tile.light,
+        lightmap,
+        redirect_dist: None,
+        redirects: 0,
     }
 }
 
@@ -179,31 +781,144 @@ pub enum Hit<'a> {
     FloorHit(FloorHit<'a>),
 }
 
+impl Hit<'_> {
+    /// How far along the ray it went through the first portal or mirror on
+    /// the way to the hit, or `None` if it went straight to it.
+    pub fn redirect_dist(&self) -> Option<f64> {
+        match self {
+            Self::WallHit(wall_hit) => wall_hit.redirect_dist,
+            Self::FloorHit(floor_hit) => floor_hit.redirect_dist,
+        }
+    }
+
+    /// How many portals and mirrors the ray went through on the way to the
+    /// hit.
+    pub fn redirects(&self) -> usize {
+        match self {
+            Self::WallHit(wall_hit) => wall_hit.redirects,
+            Self::FloorHit(floor_hit) => floor_hit.redirects,
+        }
+    }
+
+    /// Moves the hit further away, for hits behind a portal or in a mirror
+    /// that the ray reached at `dist`.
+    fn redirect(&mut self, dist: f64) {
+        match self {
+            Self::WallHit(wall_hit) => {
+                wall_hit.length += dist;
+                wall_hit.redirect_dist = Some(dist);
+                wall_hit.redirects += 1;
+            }
+            Self::FloorHit(floor_hit) => {
+                floor_hit.dist1 += dist;
+                floor_hit.dist2 += dist;
+                floor_hit.redirect_dist = Some(dist);
+                floor_hit.redirects += 1;
+            }
+        }
+    }
+}
+
 pub struct WallHit<'a> {
+    /// Distance along the ray in multiples of its direction, like all
+    /// distances of the hits. Every shape measures it that way, so for the
+    /// rays of `Camera::rays` it is the distance the projection uses.
     pub length: f64,
+    /// The map the wall is in, which is another one behind a portal that
+    /// links to it.
+    pub map: &'a Map,
+    /// Where the wall was hit, in the coordinates of the map it is in.
+    pub pos: Vector2<f64>,
     pub x: f64,
+    pub normal: Vector2<f64>,
+    /// The direction of the ray where it hit the wall, which mirrors and
+    /// portals in front of it may have turned.
+    pub dir: Vector2<f64>,
+    /// The side of the shape that was hit.
+    pub side: u32,
     pub color: &'a Color,
+    pub light: f64,
+    /// Light baked for the tile, if the map has a lightmap.
+    pub lightmap: Option<LightmapTile<'a>>,
+    /// Height of the bottom edge of the wall.
+    pub bottom: f64,
+    /// Height of the top edge of the wall.
+    pub top: f64,
+    /// See `Hit::redirect_dist`.
+    pub redirect_dist: Option<f64>,
+    /// See `Hit::redirects`.
+    pub redirects: usize,
 }
 
 pub struct FloorHit<'a> {
+    /// The map the tile is in, like `WallHit::map`.
+    pub map: &'a Map,
+    /// The tile `pos1` and `pos2` are relative to.
+    pub map_pos: Vector2<i32>,
     pub pos1: Vector2<f64>,
     pub pos2: Vector2<f64>,
     pub dist1: f64,
     pub dist2: f64,
     pub floor_color: &'a Color,
-    pub floor_height: f64,
+    /// Height of the floor at `pos1`.
+    pub floor_height1: f64,
+    /// Height of the floor at `pos2`.
+    pub floor_height2: f64,
     pub ceiling_color: &'a Color,
-    pub ceiling_height: f64,
+    pub ceiling_height1: f64,
+    pub ceiling_height2: f64,
+    pub light: f64,
+    /// Light baked for the tile, if the map has a lightmap.
+    pub lightmap: Option<LightmapTile<'a>>,
+    /// See `Hit::redirect_dist`.
+    pub redirect_dist: Option<f64>,
+    /// See `Hit::redirects`.
+    pub redirects: usize,
+}
+
+impl FloorHit<'_> {
+    /// Returns where the ray is at `dist`, in map coordinates. Distances
+    /// outside of the tile give the nearest point inside of it.
+    pub fn point_at(&self, dist: f64) -> Vector2<f64> {
+        let weight = if self.dist2 > self.dist1 {
+            ((dist - self.dist1) / (self.dist2 - self.dist1)).clamp(0.0, 1.0)
+        } else {
+            0.0
+        };
+        self.map_pos.cast().unwrap() + self.pos1 + (self.pos2 - self.pos1) * weight
+    }
+
+    /// Distance moved in the tile per unit of ray distance.
+    pub fn world_scale(&self) -> f64 {
+        if self.dist2 > self.dist1 {
+            (self.pos2 - self.pos1).magnitude() / (self.dist2 - self.dist1)
+        } else {
+            0.0
+        }
+    }
 }
 
 #[derive(Clone)]
 pub struct Tile {
     pub shape: Shape,
-    pub colors: [Color; 4],
+    /// One color for every side of the shape.
+    pub colors: Vec<Color>,
     pub floor_color: Color,
     pub floor_height: f64,
     pub ceiling_color: Color,
     pub ceiling_height: f64,
+    /// Brightness of everything in the tile, where 1 is full brightness.
+    pub light: f64,
+    /// How much the floor rises per unit along x and y, for ramps.
+    /// `floor_height` is the height at the corner of the tile at (0, 0).
+    pub floor_slope: Vector2<f64>,
+    /// How much the ceiling rises per unit along x and y.
+    pub ceiling_slope: Vector2<f64>,
+    /// Height the walls of the shape start at.
+    pub wall_bottom: f64,
+    /// Height the walls of the shape end at, or `None` for the height of the
+    /// map's walls.
+    pub wall_top: Option<f64>,
 }
 
 impl Tile {
@@ -215,21 +930,275 @@ impl Tile {
         ceiling_color: Color,
         ceiling_height: f64,
     ) -> Self {
+        Self::try_new(
+            shape,
+            colors,
+            floor_color,
+            floor_height,
+            ceiling_color,
+            ceiling_height,
+        )
+        .unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn try_new(
+        shape: Shape,
+        colors: Vec<Color>,
+        floor_color: Color,
+        floor_height: f64,
+        ceiling_color: Color,
+        ceiling_height: f64,
+    ) -> super::Result<Self> {
         if colors.len() as u32 != shape.sides() {
-            panic!("Wrong number of colors");
+            return Err(Error::WrongNumberOfColors {
+                sides: shape.sides(),
+                colors: colors.len(),
+            });
         }
-        let mut tile = Self {
+        Ok(Self {
             shape,
-            colors: [Color::Test, Color::Test, Color::Test, Color::Test],
+            colors,
             floor_color,
             floor_height,
             ceiling_color,
             ceiling_height,
+            light: 1.0,
+            floor_slope: Vector2::new(0.0, 0.0),
+            ceiling_slope: Vector2::new(0.0, 0.0),
+            wall_bottom: 0.0,
+            wall_top: None,
+        })
+    }
+
+    /// Returns the height of the floor at `pos`, given relative to the tile.
+    pub fn floor_height_at(&self, pos: Vector2<f64>) -> f64 {
+        self.floor_height + self.floor_slope.dot(pos)
+    }
+
+    /// Returns the height of the ceiling at `pos`, given relative to the tile.
+    pub fn ceiling_height_at(&self, pos: Vector2<f64>) -> f64 {
+        self.ceiling_height + self.ceiling_slope.dot(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::raycaster::Camera;
+
+    use super::*;
+
+    /// A 3x2 map with a door at (0, 0) and a portal at (1, 0).
+    fn map() -> Map {
+        let mut map = Map::new(3, 2, 1.0);
+        let door = Door {
+            panel: Line::new(Vector2::new(0.0, 0.5), Vector2::new(1.0, 0.5)),
+            kind: DoorKind::Sliding,
+            speed: 1.0,
+        };
+        let portal = Portal {
+            face: Line::new(Vector2::new(0.0, 0.5), Vector2::new(1.0, 0.5)),
+        };
+        let tile = |shape: Shape| {
+            let colors = vec![Color::Test; shape.sides() as usize];
+            Tile::new(shape, colors, Color::Test, 0.0, Color::Test, 1.0)
+        };
+        map.set_tile(0, 0, tile(Shape::Door(door)));
+        map.set_tile(1, 0, tile(Shape::Portal(portal)));
+        map
+    }
+
+    fn link(x: usize, y: usize) -> PortalLink {
+        PortalLink {
+            target: Vector2::new(x, y),
+            rotation: 0.0,
+            map: None,
+        }
+    }
+
+    #[test]
+    fn doors() {
+        let mut map = map();
+        assert_eq!(map.door_state(0, 0), Some(DoorState::Closed));
+        map.set_door_state(0, 0, DoorState::Open).unwrap();
+        map.update(0.25);
+        assert_eq!(map.door_state(0, 0), Some(DoorState::Open));
+        assert_eq!(map.door_open(0, 0), Some(0.25));
+    }
+
+    #[test]
+    fn door_errors() {
+        let mut map = map();
+        assert!(matches!(
+            map.set_door_state(1, 1, DoorState::Open),
+            Err(Error::NotADoor { x: 1, y: 1 })
+        ));
+        assert!(matches!(
+            map.set_door_state(3, 0, DoorState::Open),
+            Err(Error::OutOfBounds {
+                x: 3,
+                y: 0,
+                width: 3,
+                height: 2
+            })
+        ));
+        assert_eq!(map.door_state(1, 1), None);
+        assert_eq!(map.door_open(0, 2), None);
+    }
+
+    #[test]
+    fn replaced_doors() {
+        let mut map = map();
+        map.set_door_state(0, 0, DoorState::Open).unwrap();
+        map.set_tile(
+            0,
+            0,
+            Tile::new(Shape::Void, vec![], Color::Test, 0.0, Color::Test, 1.0),
+        );
+        map.update(0.25);
+        assert_eq!(map.door_open(0, 0), None);
+    }
+
+    #[test]
+    fn portal_errors() {
+        let mut map = map();
+        map.set_portal_link(1, 0, link(2, 1)).unwrap();
+        assert!(map.portal_link(1, 0).is_some());
+        assert!(map.portal_link(1, 2).is_none());
+        assert!(matches!(
+            map.set_portal_link(0, 0, link(2, 1)),
+            Err(Error::NotAPortal { x: 0, y: 0 })
+        ));
+        assert!(matches!(
+            map.set_portal_link(1, 5, link(2, 1)),
+            Err(Error::OutOfBounds { x: 1, y: 5, .. })
+        ));
+        assert!(matches!(
+            map.set_portal_link(1, 0, link(2, 2)),
+            Err(Error::OutOfBounds { x: 2, y: 2, .. })
+        ));
+    }
+
+    #[test]
+    fn tile_errors() {
+        let mut map = map();
+        let void = Tile::new(Shape::Void, vec![], Color::Test, 0.0, Color::Test, 1.0);
+        assert!(matches!(
+            map.try_set_tile(0, 2, void.clone()),
+            Err(Error::OutOfBounds { x: 0, y: 2, .. })
+        ));
+        assert!(matches!(
+            map.add_layer(1.0).try_set_tile(3, 0, Some(void)),
+            Err(Error::OutOfBounds { x: 3, y: 0, .. })
+        ));
+        assert!(matches!(
+            Tile::try_new(Shape::Box, vec![], Color::Test, 0.0, Color::Test, 1.0),
+            Err(Error::WrongNumberOfColors {
+                sides: 4,
+                colors: 0
+            })
+        ));
+    }
+
+    #[test]
+    fn portals_block_line_of_sight() {
+        let mut map = map();
+        let front = (Vector2::new(1.5, 0.9), 0.5);
+        let back = (Vector2::new(1.5, 0.1), 0.5);
+        assert!(map.line_of_sight(front, back));
+        map.set_portal_link(1, 0, link(2, 1)).unwrap();
+        assert!(!map.line_of_sight(front, back));
+        // The back of the face is not a portal.
+        assert!(map.line_of_sight(back, front));
+    }
+
+    #[test]
+    fn layer_walls_in_order() {
+        // A wall at the near end of the tile in the bottom grid and one at the
+        // far end in a layer.
+        let wall = |y| {
+            let line = Line::new(Vector2::new(0.0, y), Vector2::new(1.0, y));
+            Tile::new(
+                Shape::Line(line),
+                vec![Color::Test; 2],
+                Color::Test,
+                0.0,
+                Color::Test,
+                1.0,
+            )
         };
-        for i in 0..colors.len() {
-            tile.colors[i] = colors[i].clone();
+        let mut map = Map::new(1, 1, 1.0);
+        map.set_tile(0, 0, wall(0.2));
+        map.add_layer(1.0).set_tile(0, 0, Some(wall(0.8)));
+
+        let mut walls = Vec::new();
+        map.ray_cast(Vector2::new(0.5, 0.0), Vector2::new(0.0, 1.0), &mut |hit| {
+            if let Hit::WallHit(wall_hit) = hit {
+                walls.push((wall_hit.length, wall_hit.bottom));
+            }
+            false
+        });
+        assert_eq!(walls.len(), 2);
+        assert!((walls[0].0 - 0.2).abs() < 1e-9 && walls[0].1 == 0.0);
+        assert!((walls[1].0 - 0.8).abs() < 1e-9 && walls[1].1 == 1.0);
+    }
+
+    /// The first wall the ray of column `x` of 8 of `camera` hits in a map
+    /// with `shape` in every tile of column 3.
+    fn first_wall_length(camera: &Camera, x: usize, shape: Shape) -> f64 {
+        let mut map = Map::new(5, 5, 1.0);
+        for y in 0..5 {
+            let colors = vec![Color::Test; shape.sides() as usize];
+            let tile = Tile::new(shape.clone(), colors, Color::Test, 0.0, Color::Test, 1.0);
+            map.set_tile(3, y, tile);
         }
+        let ray_dir = camera.rays(8).nth(x).unwrap();
+        let mut length = None;
+        map.ray_cast(camera.pos(), ray_dir, &mut |hit| match hit {
+            Hit::WallHit(wall_hit) => {
+                length = Some(wall_hit.length);
+                true
+            }
+            Hit::FloorHit(_) => false,
+        });
+        length.unwrap()
+    }
+
+    #[test]
+    fn wall_lengths_are_perpendicular() {
+        // Column 3 of 8 looks along (1, -0.25), a quarter of the way to the
+        // left edge of the screen.
+        let camera = Camera::new(Vector2::new(0.5, 2.5), 0.0, f64::to_radians(90.0));
+        let line = Line::new(Vector2::new(0.5, 0.0), Vector2::new(0.5, 1.0));
+        let length = first_wall_length(&camera, 3, Shape::Line(line));
+        assert!((length - 3.0).abs() < 1e-9, "{}", length);
+
+        // The ray goes through the center of the circle at (3.5, 1.75).
+        let circle = Circle {
+            pos: Vector2::new(0.5, 0.75),
+            radius: 0.25,
+        };
+        let length = first_wall_length(&camera, 3, Shape::Circle(circle));
+        let hit = Vector2::new(3.5, 1.75) - Vector2::new(1.0, -0.25).normalize() * 0.25;
+        let expected = (hit - camera.pos()).dot(camera.dir());
+        assert!(
+            (length - expected).abs() < 1e-9,
+            "{} != {}",
+            length,
+            expected
+        );
+    }
 
-        tile
+    #[test]
+    fn file_errors() {
+        let missing = Path::new("maps/missing/missing.map");
+        assert!(matches!(Map::load(missing), Err(Error::Io { path, .. }) if path == missing));
+        assert!(matches!(map().save(missing), Err(Error::Io { path, .. }) if path == missing));
+        let lightmap = Lightmap::bake(&map(), 1);
+        assert!(
+            matches!(lightmap.save(missing), Err(Error::Image { path, .. }) if path == missing)
+        );
+        assert!(
+            matches!(Lightmap::load(missing, &map()), Err(Error::Image { path, .. }) if path == missing)
+        );
     }
 }
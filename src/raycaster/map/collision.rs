@@ -0,0 +1,416 @@
+use std::ops::Range;
+
+use cgmath::{InnerSpace, Vector2};
+
+use super::*;
+
+/// How many times overlaps are resolved per step, so that pushing out of one
+/// tile and into another settles.
+const RESOLVE_ITERATIONS: usize = 4;
+
+/// Shortest step a move is split into, for circles too small to set the step
+/// length.
+const MIN_STEP: f64 = 0.05;
+
+/// How far the circle reaches at least above the highest floor it can step
+/// onto, so that walls stop it even when its top is down at the floor.
+const MIN_BODY_HEIGHT: f64 = 0.05;
+
+impl Map {
+    /// Moves a circle from `from` towards `to`, sliding along everything in
+    /// the way, and returns where it ends up. The circle reaches up to `z`,
+    /// like the eye of a camera, but always a little above what it can step
+    /// onto, and stands on the floor below that. Tiles
+    /// with a floor more than `step_height` above it block the whole tile,
+    /// and walls that end no higher than that are stepped over, as are the
+    /// walls and floors of layers that start above `z`. A circle with no
+    /// radius is moved as a point, which can pass through lines.
+    pub fn move_circle(
+        &self,
+        from: Vector2<f64>,
+        to: Vector2<f64>,
+        radius: f64,
+        z: f64,
+        step_height: f64,
+    ) -> Vector2<f64> {
+        let max_floor = self.floor_height_at(from, z).unwrap_or(0.0) + step_height;
+        let heights = max_floor..z.max(max_floor + MIN_BODY_HEIGHT);
+
+        // Small steps keep fast movement from tunneling through thin shapes.
+        let delta = to - from;
+        let step = (radius * 0.5).max(MIN_STEP);
+        let steps = (delta.magnitude() / step).ceil().max(1.0) as usize;
+        let mut pos = from;
+        for _ in 0..steps {
+            pos += delta / steps as f64;
+            for _ in 0..RESOLVE_ITERATIONS {
+                let mut pushed = false;
+                for y in (pos.y - radius).floor() as i32..=(pos.y + radius).floor() as i32 {
+                    for x in (pos.x - radius).floor() as i32..=(pos.x + radius).floor() as i32 {
+                        let tile_pos = Vector2::new(x as f64, y as f64);
+                        if let Some(push) =
+                            self.tile_penetration(x, y, pos - tile_pos, radius, heights.clone())
+                        {
+                            pos += push;
+                            pushed = true;
+                        }
+                    }
+                }
+                if !pushed {
+                    break;
+                }
+            }
+        }
+        pos
+    }
+
+    /// Returns where a point that moves from `from` to `to` ends up, and how
+    /// much it is turned, if it walks through the front of a portal. Only
+    /// portals that lead to a tile in this map are followed, and
+    /// `move_circle` does not let circles through the others.
+    pub fn portal_crossing(
+        &self,
+        from: Vector2<f64>,
+        to: Vector2<f64>,
+    ) -> Option<(Vector2<f64>, f64)> {
+        let tiles = [from, to].map(|p| Vector2::new(p.x.floor() as i32, p.y.floor() as i32));
+        for map_pos in tiles {
+            let portal = match self.get_tile(map_pos.x, map_pos.y) {
+                Some(Tile {
+                    shape: Shape::Portal(portal),
+                    ..
+                }) => portal,
+                _ => continue,
+            };
+            let link = match self
+                .portals
+                .get(&(map_pos.y as usize * self.width + map_pos.x as usize))
+            {
+                Some(link) if link.map.is_none() => link,
+                _ => continue,
+            };
+            let tile_pos: Vector2<f64> = map_pos.cast().unwrap();
+            match portal.ray_cast(from - tile_pos, to - from) {
+                Some(info) if info.length <= 1.0 => {
+                    let (pos, _) = link.transform(to - tile_pos, to - from);
+                    return Some((pos, link.rotation));
+                }
+                _ => {}
+            }
+        }
+        None
+    }
+
+    /// Returns the height of the highest floor at `pos` that is no higher
+    /// than `z`, counting the floors of the layers, or of the floor of the
+    /// bottom grid if they are all higher.
+    pub fn floor_height_at(&self, pos: Vector2<f64>, z: f64) -> Option<f64> {
+        let map_pos = pos.map(|a| a.floor() as i32);
+        let tile_pos: Vector2<f64> = map_pos.cast().unwrap();
+        let floor = self.tile(map_pos)?.floor_height_at(pos - tile_pos);
+        let layer_floors = self.layers.iter().filter_map(|layer| {
+            let tile = layer.tile(map_pos)?;
+            Some(layer.elevation + tile.floor_height_at(pos - tile_pos))
+        });
+        Some(
+            layer_floors
+                .filter(|&height| height <= z)
+                .fold(floor, f64::max),
+        )
+    }
+
+    /// Tiles outside the map and tiles that are too high to step onto are
+    /// solid. Otherwise only the walls and layers that reach into `heights`,
+    /// from the highest floor that can be stepped onto up to the top of the
+    /// circle, are in the way.
+    fn tile_penetration(
+        &self,
+        x: i32,
+        y: i32,
+        center: Vector2<f64>,
+        radius: f64,
+        heights: Range<f64>,
+    ) -> Option<Vector2<f64>> {
+        let map_pos = Vector2::new(x, y);
+        // Sloped floors are compared where the circle is closest to them.
+        let closest = center.map(|a| a.clamp(0.0, 1.0));
+        let tile = match self.tile(map_pos) {
+            Some(tile) if tile.floor_height_at(closest) <= heights.start => tile,
+            _ => return Shape::Box.penetration(center, radius),
+        };
+        let door_open = self.door_open(x as usize, y as usize).unwrap_or(0.0);
+        let mut push = None;
+        if tile.wall_top.unwrap_or(self.wall_height) > heights.start
+            && tile.wall_bottom < heights.end
+        {
+            push = match &tile.shape {
+                // Only the map itself can be walked around in, so a portal to
+                // another map is a wall.
+                Shape::Portal(portal) => match self.portal_link(x as usize, y as usize) {
+                    Some(PortalLink { map: Some(_), .. }) => portal.penetration(center, radius),
+                    _ => None,
+                },
+                shape => shape.penetration_all(center, radius, door_open),
+            };
+        }
+        for layer in &self.layers {
+            let Some(tile) = layer.tile(map_pos) else {
+                continue;
+            };
+            let elevation = layer.elevation;
+            if elevation >= heights.end {
+                continue;
+            }
+            // The layer is solid from its elevation up to the floor of its
+            // tiles.
+            let floor = elevation + tile.floor_height_at(closest);
+            let top = elevation + tile.wall_top.unwrap_or(self.wall_height);
+            let overlap = if floor > heights.start {
+                Shape::Box.penetration(center, radius)
+            } else if top > heights.start && elevation + tile.wall_bottom < heights.end {
+                tile.shape.penetration_all(center, radius, 0.0)
+            } else {
+                None
+            };
+            // The deepest overlap is resolved first.
+            push = match (push, overlap) {
+                (Some(a), Some(b)) if b.magnitude2() > a.magnitude2() => Some(b),
+                (push, overlap) => push.or(overlap),
+            };
+        }
+        push
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tile(shape: Shape, colors: usize, floor_height: f64) -> Tile {
+        Tile::new(
+            shape,
+            vec![Color::Test; colors],
+            Color::Test,
+            floor_height,
+            Color::Test,
+            1.0,
+        )
+    }
+
+    /// A 6x5 map with nothing in it but `tile` at (3, 2).
+    fn map_with(tile: Tile) -> Map {
+        let mut map = Map::new(6, 5, 1.0);
+        map.set_tile(3, 2, tile);
+        map
+    }
+
+    fn assert_near(actual: Vector2<f64>, expected: Vector2<f64>) {
+        assert!(
+            (actual - expected).magnitude() < 1e-9,
+            "expected {:?} but got {:?}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn slides_along_walls() {
+        let map = map_with(tile(Shape::Box, 4, 0.0));
+        let to = map.move_circle(
+            Vector2::new(2.5, 2.2),
+            Vector2::new(3.5, 2.7),
+            0.25,
+            0.5,
+            0.0,
+        );
+        assert_near(to, Vector2::new(2.75, 2.7));
+    }
+
+    #[test]
+    fn walls_stop_circles_at_the_floor() {
+        let map = map_with(tile(Shape::Box, 4, 0.0));
+        let to = map.move_circle(
+            Vector2::new(2.5, 2.5),
+            Vector2::new(4.5, 2.5),
+            0.25,
+            0.0,
+            0.3,
+        );
+        assert_near(to, Vector2::new(2.75, 2.5));
+    }
+
+    #[test]
+    fn stays_inside_the_map() {
+        let map = map_with(tile(Shape::Void, 0, 0.0));
+        let to = map.move_circle(
+            Vector2::new(0.5, 0.5),
+            Vector2::new(-1.0, 0.5),
+            0.25,
+            0.5,
+            0.0,
+        );
+        assert_near(to, Vector2::new(0.25, 0.5));
+    }
+
+    #[test]
+    fn step_height() {
+        let map = map_with(tile(Shape::Void, 0, 0.3));
+        let (from, to) = (Vector2::new(2.5, 2.5), Vector2::new(3.5, 2.5));
+        assert_near(
+            map.move_circle(from, to, 0.25, 0.5, 0.2),
+            Vector2::new(2.75, 2.5),
+        );
+        assert_near(map.move_circle(from, to, 0.25, 0.5, 0.3), to);
+    }
+
+    /// `map_with` a ramp at (3, 2) that rises by `slope` per unit of x, and
+    /// a floor at (4, 2) as high as the top of the ramp.
+    fn ramp(slope: f64) -> Map {
+        let mut ramp = tile(Shape::Void, 0, 0.0);
+        ramp.floor_slope = Vector2::new(slope, 0.0);
+        let mut map = map_with(ramp);
+        map.set_tile(4, 2, tile(Shape::Void, 0, slope));
+        map
+    }
+
+    /// Moves a circle towards `to` in small steps, like a player walking
+    /// over several frames.
+    fn walk(map: &Map, from: Vector2<f64>, to: Vector2<f64>, step_height: f64) -> Vector2<f64> {
+        let mut pos = from;
+        for i in 1..=20 {
+            pos = map.move_circle(
+                pos,
+                from + (to - from) * (i as f64 / 20.0),
+                0.25,
+                1.5,
+                step_height,
+            );
+        }
+        pos
+    }
+
+    #[test]
+    fn walks_up_ramps() {
+        let map = ramp(0.5);
+        let (from, to) = (Vector2::new(2.5, 2.5), Vector2::new(4.5, 2.5));
+        assert_near(walk(&map, from, to, 0.2), to);
+        assert_eq!(map.floor_height_at(to, 1.5), Some(0.5));
+    }
+
+    #[test]
+    fn steep_slopes_block() {
+        let map = ramp(3.0);
+        let (from, to) = (Vector2::new(2.5, 2.5), Vector2::new(4.5, 2.5));
+        let end = walk(&map, from, to, 0.2);
+        assert!(end.x <= 3.0, "ended up at {:?}", end);
+    }
+
+    #[test]
+    fn steps_over_low_walls() {
+        let mut low_wall = tile(Shape::Box, 4, 0.0);
+        low_wall.wall_top = Some(0.2);
+        let map = map_with(low_wall);
+        let (from, to) = (Vector2::new(2.5, 2.5), Vector2::new(3.5, 2.5));
+        assert_near(
+            map.move_circle(from, to, 0.25, 0.5, 0.1),
+            Vector2::new(2.75, 2.5),
+        );
+        assert_near(map.move_circle(from, to, 0.25, 0.5, 0.2), to);
+    }
+
+    #[test]
+    fn walks_under_high_walls() {
+        let mut lintel = tile(Shape::Box, 4, 0.0);
+        lintel.wall_bottom = 0.8;
+        let map = map_with(lintel);
+        let (from, to) = (Vector2::new(2.5, 2.5), Vector2::new(3.5, 2.5));
+        assert_near(map.move_circle(from, to, 0.25, 0.5, 0.0), to);
+        assert_near(
+            map.move_circle(from, to, 0.25, 1.0, 0.0),
+            Vector2::new(2.75, 2.5),
+        );
+    }
+
+    /// `map_with(tile)` with a balcony at a height of 1 over (2, 2) and a
+    /// box on it at (3, 2).
+    fn balcony(tile: Tile) -> Map {
+        let mut map = map_with(tile);
+        let layer = map.add_layer(1.0);
+        layer.set_tile(2, 2, Some(self::tile(Shape::Void, 0, 0.0)));
+        layer.set_tile(3, 2, Some(self::tile(Shape::Box, 4, 0.0)));
+        map
+    }
+
+    #[test]
+    fn floors_of_layers() {
+        let map = balcony(tile(Shape::Void, 0, 0.0));
+        let pos = Vector2::new(2.5, 2.5);
+        assert_eq!(map.floor_height_at(pos, 0.5), Some(0.0));
+        assert_eq!(map.floor_height_at(pos, 1.5), Some(1.0));
+        assert_eq!(map.floor_height_at(Vector2::new(1.5, 2.5), 1.5), Some(0.0));
+    }
+
+    #[test]
+    fn walls_of_layers() {
+        let map = balcony(tile(Shape::Void, 0, 0.0));
+        let (from, to) = (Vector2::new(2.5, 2.5), Vector2::new(3.5, 2.5));
+        assert_near(map.move_circle(from, to, 0.25, 0.5, 0.3), to);
+        assert_near(
+            map.move_circle(from, to, 0.25, 1.5, 0.3),
+            Vector2::new(2.75, 2.5),
+        );
+    }
+
+    #[test]
+    fn portals_to_other_maps_block() {
+        let face = Line::new(Vector2::new(0.5, 0.0), Vector2::new(0.5, 1.0));
+        let mut map = map_with(tile(Shape::Portal(Portal { face }), 0, 0.0));
+        let link = |map| PortalLink {
+            target: Vector2::new(1, 1),
+            rotation: 0.0,
+            map,
+        };
+        let (from, to) = (Vector2::new(3.1, 2.5), Vector2::new(3.9, 2.5));
+        map.set_portal_link(3, 2, link(None)).unwrap();
+        assert_near(map.move_circle(from, to, 0.25, 0.5, 0.3), to);
+        assert!(map.portal_crossing(from, to).is_some());
+
+        let other = Arc::new(map_with(tile(Shape::Void, 0, 0.0)));
+        map.set_portal_link(3, 2, link(Some(other))).unwrap();
+        assert_near(
+            map.move_circle(from, to, 0.25, 0.5, 0.3),
+            Vector2::new(3.25, 2.5),
+        );
+        assert!(map.portal_crossing(from, to).is_none());
+        // The back of the face is not in the way.
+        assert!(map.move_circle(to, from, 0.25, 0.5, 0.3).x < 3.25);
+    }
+
+    #[test]
+    fn does_not_tunnel_through_thin_walls() {
+        let line = Line::new(Vector2::new(0.5, 0.0), Vector2::new(0.5, 1.0));
+        let map = map_with(tile(Shape::Line(line), 2, 0.0));
+        let to = map.move_circle(
+            Vector2::new(2.5, 2.5),
+            Vector2::new(5.5, 2.5),
+            0.1,
+            0.5,
+            0.0,
+        );
+        assert_near(to, Vector2::new(3.4, 2.5));
+    }
+
+    #[test]
+    fn moves_points() {
+        let map = map_with(tile(Shape::Box, 4, 0.0));
+        let to = map.move_circle(
+            Vector2::new(0.5, 2.5),
+            Vector2::new(5.5, 2.5),
+            0.0,
+            0.5,
+            0.0,
+        );
+        assert!(to.x <= 3.0 && to.x > 2.9, "ended up at {:?}", to);
+        assert_eq!(to.y, 2.5);
+    }
+}
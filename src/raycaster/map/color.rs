@@ -1,19 +1,142 @@
-use cgmath::Vector2;
+use cgmath::{Matrix2, Vector2};
 
 use image::io::Reader as ImageReader;
 use image::DynamicImage;
-use std::rc::Rc;
+use std::sync::Arc;
+
+use crate::raycaster::{Error, Result};
 
 #[derive(Clone)]
 pub enum Color {
     Solid([f64; 4]),
     Test,
     Test2,
-    Texture(Rc<Texture>),
+    Texture(Arc<Texture>, TextureMapping),
+    /// Cycles through `frames`, showing `rate` frames per second.
+    Animated {
+        frames: Vec<Color>,
+        rate: f64,
+    },
+    /// Moves the texture coordinates of `color` by `velocity` per second.
+    Scrolling {
+        color: Box<Color>,
+        velocity: Vector2<f64>,
+    },
+    /// A mirror, where `reflectivity` is the part of the wall taken up by the
+    /// reflection and the rest shows `color`. Only wall sides reflect.
+    Reflective {
+        color: Box<Color>,
+        reflectivity: f64,
+    },
+}
+
+/// How texture coordinates outside of [0, 1) are mapped onto the texture.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Wrap {
+    /// The texture tiles endlessly.
+    Repeat,
+    /// The edge texels are stretched outwards.
+    Clamp,
+    /// The texture tiles, flipping every other copy.
+    Mirror,
+}
+
+impl Wrap {
+    /// Maps a texel index that may be outside of the texture into it.
+    fn index(self, i: i64, size: usize) -> usize {
+        let size = size as i64;
+        let i = match self {
+            Self::Repeat => i.rem_euclid(size),
+            Self::Clamp => i.clamp(0, size - 1),
+            Self::Mirror => {
+                let i = i.rem_euclid(2 * size);
+                if i < size {
+                    i
+                } else {
+                    2 * size - 1 - i
+                }
+            }
+        };
+        i as usize
+    }
+}
+
+/// Transforms the texture coordinates of a surface before the texture is
+/// sampled. The coordinates are scaled, then rotated around the origin and
+/// then offset.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct TextureMapping {
+    pub wrap: Wrap,
+    pub scale: Vector2<f64>,
+    pub offset: Vector2<f64>,
+    /// Rotation in radians.
+    pub rotation: f64,
+}
+
+impl Default for TextureMapping {
+    fn default() -> Self {
+        Self {
+            wrap: Wrap::Repeat,
+            scale: Vector2::new(1.0, 1.0),
+            offset: Vector2::new(0.0, 0.0),
+            rotation: 0.0,
+        }
+    }
+}
+
+impl TextureMapping {
+    pub fn apply(&self, pos: Vector2<f64>) -> Vector2<f64> {
+        let scaled = Vector2::new(pos.x * self.scale.x, pos.y * self.scale.y);
+        let (sin, cos) = self.rotation.sin_cos();
+        Matrix2::new(cos, sin, -sin, cos) * scaled + self.offset
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Filter {
+    /// Nearest texel of the full resolution texture.
+    Nearest,
+    /// Bilinear interpolation in the closest mip level.
+    Bilinear,
+    /// Bilinear interpolation blended between the two closest mip levels.
+    Trilinear,
+}
+
+#[derive(Clone, Copy)]
+pub struct Sampler {
+    pub filter: Filter,
+    /// Texture coordinate distance covered by one screen pixel, used to pick
+    /// the mip level.
+    pub footprint: f64,
+    /// Seconds since the start of the animation.
+    pub time: f64,
 }
 
 impl Color {
-    pub fn sample(&self, pos: Vector2<f64>) -> [f64; 4] {
+    /// A texture covering each surface exactly once.
+    pub fn texture(texture: Arc<Texture>) -> Self {
+        Self::Texture(texture, TextureMapping::default())
+    }
+
+    /// Animates through a texture that holds `frames` frames side by side.
+    pub fn strip(texture: Arc<Texture>, frames: usize, rate: f64) -> Self {
+        let frames = (0..frames)
+            .map(|i| {
+                Self::Texture(
+                    texture.clone(),
+                    TextureMapping {
+                        wrap: Wrap::Clamp,
+                        scale: Vector2::new(1.0 / frames as f64, 1.0),
+                        offset: Vector2::new(i as f64 / frames as f64, 0.0),
+                        rotation: 0.0,
+                    },
+                )
+            })
+            .collect();
+        Self::Animated { frames, rate }
+    }
+
+    pub fn sample(&self, pos: Vector2<f64>, sampler: &Sampler) -> [f64; 4] {
         match self {
             Self::Solid(color) => *color,
             Self::Test => [pos.x, pos.y, 0.0, 1.0],
@@ -24,49 +147,184 @@ impl Color {
                 }
                 color
             }
-            Self::Texture(texture) => texture.sample(pos.x, pos.y),
+            Self::Texture(texture, mapping) => {
+                let uv = mapping.apply(pos);
+                let sampler = Sampler {
+                    footprint: sampler.footprint * mapping.scale.x.abs().max(mapping.scale.y.abs()),
+                    ..*sampler
+                };
+                texture.sample(uv.x, uv.y, mapping.wrap, &sampler)
+            }
+            Self::Animated { frames, rate } => {
+                if frames.is_empty() {
+                    return [0.0; 4];
+                }
+                let frame = (sampler.time * rate).floor() as i64;
+                frames[frame.rem_euclid(frames.len() as i64) as usize].sample(pos, sampler)
+            }
+            Self::Scrolling { color, velocity } => {
+                color.sample(pos + velocity * sampler.time, sampler)
+            }
+            Self::Reflective { color, .. } => color.sample(pos, sampler),
         }
     }
 }
 
 pub struct Texture {
+    path: String,
+    /// Mip chain, from the full resolution image down to 1x1.
+    levels: Vec<MipLevel>,
+}
+
+struct MipLevel {
     width: usize,
     height: usize,
     data: Vec<u8>,
 }
 
+impl MipLevel {
+    fn texel(&self, x: usize, y: usize) -> [f64; 4] {
+        let mut color = [0.0; 4];
+        let index = (y * self.width + x) * 4;
+        for (i, c) in color.iter_mut().enumerate() {
+            *c = self.data[index + i] as f64 / 255.0;
+        }
+        color
+    }
+
+    /// Halves the size by averaging 2x2 blocks of texels.
+    fn downsample(&self) -> Self {
+        let width = (self.width / 2).max(1);
+        let height = (self.height / 2).max(1);
+        let mut data = Vec::with_capacity(width * height * 4);
+        for y in 0..height {
+            for x in 0..width {
+                for i in 0..4 {
+                    let mut sum = 0;
+                    for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                        let sx = (x * 2 + dx).min(self.width - 1);
+                        let sy = (y * 2 + dy).min(self.height - 1);
+                        sum += self.data[(sy * self.width + sx) * 4 + i] as u32;
+                    }
+                    data.push(((sum + 2) / 4) as u8);
+                }
+            }
+        }
+        Self {
+            width,
+            height,
+            data,
+        }
+    }
+
+    fn sample_nearest(&self, x: f64, y: f64, wrap: Wrap) -> [f64; 4] {
+        let xi = (x * self.width as f64).floor() as i64;
+        let yi = (y * self.height as f64).floor() as i64;
+        self.texel(wrap.index(xi, self.width), wrap.index(yi, self.height))
+    }
+
+    fn sample_bilinear(&self, x: f64, y: f64, wrap: Wrap) -> [f64; 4] {
+        let fx = x * self.width as f64 - 0.5;
+        let fy = y * self.height as f64 - 0.5;
+        let tx = fx - fx.floor();
+        let ty = fy - fy.floor();
+        let x0 = fx.floor() as i64;
+        let y0 = fy.floor() as i64;
+        let (x0, x1) = (wrap.index(x0, self.width), wrap.index(x0 + 1, self.width));
+        let (y0, y1) = (wrap.index(y0, self.height), wrap.index(y0 + 1, self.height));
+
+        let mut color = [0.0; 4];
+        let corners = [
+            (self.texel(x0, y0), (1.0 - tx) * (1.0 - ty)),
+            (self.texel(x1, y0), tx * (1.0 - ty)),
+            (self.texel(x0, y1), (1.0 - tx) * ty),
+            (self.texel(x1, y1), tx * ty),
+        ];
+        for (texel, weight) in corners {
+            for i in 0..4 {
+                color[i] += texel[i] * weight;
+            }
+        }
+        color
+    }
+}
+
 impl Texture {
     pub fn new(path: &str) -> Self {
-        let img = ImageReader::open(path).unwrap().decode().unwrap();
+        Self::load(path).unwrap_or_else(|err| panic!("{}", err))
+    }
+
+    pub fn load(path: &str) -> Result<Self> {
+        let img = ImageReader::open(path)
+            .map_err(|source| Error::Io {
+                path: path.into(),
+                source,
+            })?
+            .decode()
+            .map_err(|source| Error::Image {
+                path: path.into(),
+                source,
+            })?;
         let width = img.width();
         let height = img.height();
         let data = if let DynamicImage::ImageRgba8(rgba8) = img {
             rgba8.into_raw()
         } else {
-            let rgba8 = img.as_rgba8().unwrap();
-            let mut pixels = Vec::<u8>::new();
-            for pixel in rgba8.pixels() {
-                for i in 0..4 {
-                    pixels.push(pixel[i]);
-                }
-            }
-            pixels
+            img.to_rgba8().into_raw()
         };
-        Self {
+
+        let mut levels = vec![MipLevel {
             width: width as usize,
             height: height as usize,
             data,
+        }];
+        while let Some(last) = levels.last().filter(|l| l.width > 1 || l.height > 1) {
+            levels.push(last.downsample());
         }
+        Ok(Self {
+            path: path.to_owned(),
+            levels,
+        })
     }
 
-    pub fn sample(&self, x: f64, y: f64) -> [f64; 4] {
-        let mut color = [0.0; 4];
-        let xi = (x * (self.width as f64)) as usize;
-        let yi = (y * (self.height as f64)) as usize;
-        let index = (yi * self.width + xi) * 4;
-        for i in 0..4 {
-            color[i] = self.data[index + i] as f64 / 255.0;
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn width(&self) -> usize {
+        self.levels[0].width
+    }
+
+    pub fn height(&self) -> usize {
+        self.levels[0].height
+    }
+
+    pub fn sample(&self, x: f64, y: f64, wrap: Wrap, sampler: &Sampler) -> [f64; 4] {
+        match sampler.filter {
+            Filter::Nearest => self.levels[0].sample_nearest(x, y, wrap),
+            Filter::Bilinear => {
+                let lod = self.lod(sampler.footprint).round() as usize;
+                self.levels[lod].sample_bilinear(x, y, wrap)
+            }
+            Filter::Trilinear => {
+                let lod = self.lod(sampler.footprint);
+                let lower = lod.floor() as usize;
+                let upper = (lower + 1).min(self.levels.len() - 1);
+                let t = lod - lower as f64;
+                let a = self.levels[lower].sample_bilinear(x, y, wrap);
+                let b = self.levels[upper].sample_bilinear(x, y, wrap);
+                let mut color = [0.0; 4];
+                for i in 0..4 {
+                    color[i] = a[i] * (1.0 - t) + b[i] * t;
+                }
+                color
+            }
         }
-        color
+    }
+
+    /// Returns the mip level where one texel covers about one screen pixel.
+    fn lod(&self, footprint: f64) -> f64 {
+        let texels = footprint * self.width().max(self.height()) as f64;
+        texels.log2().clamp(0.0, (self.levels.len() - 1) as f64)
     }
 }
@@ -0,0 +1,1873 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write as _};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::raycaster::{Error, PointLight};
+
+use cgmath::Vector2;
+use log::warn;
+
+use super::*;
+
+// A map file is a list of line based directives. `#` starts a comment line.
+//
+//     size 10 10
+//     wall_height 1
+//     sky texture "textures/sky.png"
+//
+//     tile #
+//         shape box
+//         color test2
+//         color texture "textures/wall1.png"
+//         color texture "textures/wall1.png" wrap mirror scale 2 2 offset 0.5 0 rotate 90
+//         ...
+//         floor 0 test
+//         ceiling 1 test
+//         light 1
+//         wall 0 0.5
+//     end
+//
+//     grid
+//     ##########
+//     #........#
+//     ...
+//     end
+//
+// A texture path can be followed by `wrap repeat|clamp|mirror`, `scale X Y`,
+// `offset X Y` and `rotate DEGREES`, in any order. Colors can also be animated:
+//
+//     color animated 2 [ solid 1 0 0 1 texture "textures/wall1.png" ]
+//     color strip "textures/wall1.png" 4 8
+//     floor 0 scroll 0 0.5 texture "textures/wall1.png"
+//
+// `animated RATE [ COLOR... ]` shows RATE of the listed colors per second,
+// `strip PATH FRAMES RATE` does the same with frames placed side by side in
+// one texture, and `scroll X Y COLOR` moves the texture coordinates of COLOR
+// by X and Y per second. Mirrors are written as `reflective AMOUNT COLOR`.
+//
+// A tile with more than one `shape` line holds all of the shapes, and its
+// colors are listed for the sides of the first shape, then the second, and so
+// on. Portals cannot be combined with other shapes.
+//
+// `floor_slope X Y` and `ceiling_slope X Y` make ramps that rise by X per unit
+// along x and Y per unit along y, starting from the height given for the
+// corner of the tile at (0, 0).
+//
+// `wall BOTTOM TOP` sets the heights the walls of a tile span, so the ray
+// continues above and below them. Without TOP the walls reach up to
+// `wall_height`. A tile with a floor as high as its walls is a raised
+// platform, and walls no higher than a step can be walked onto.
+//
+// Every character in the grid refers to a tile defined with `tile`. Rows are
+// listed from y = 0 and columns from x = 0.
+//
+// Layers of tiles stacked above the grid are written like the grid, with the
+// elevation of the layer, and a space where the layer has no tile. They can be
+// walked on and under, but doors, portals and mirrors only work in the grid:
+//
+//     layer 1
+//     ##########
+//     #  ...   #
+//     ...
+//     end
+//
+// Tiles with a `portal` shape are linked with
+//
+//     portal X Y TARGET_X TARGET_Y ROTATION
+//     portal X Y TARGET_X TARGET_Y ROTATION map "other.map"
+//
+// where the rotation is in degrees and the second form links to a tile in
+// another map file, given relative to the directory of this one.
+//
+// Static lights are listed with
+//
+//     point_light X Y Z RED GREEN BLUE RADIUS INTENSITY
+//
+// and only show up once baked into a lightmap with `raycaster-cpu bake`. The
+// lightmap is stored next to the map file, as `NAME.lightmap.png` for
+// `NAME.map`, and is loaded along with the map. A lightmap baked before the
+// lights or the shapes and heights of the tiles changed is ignored.
+
+#[derive(Debug)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}: {}", self.line, self.column, self.message)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl Map {
+    pub fn load<P: AsRef<Path>>(path: P) -> crate::raycaster::Result<Self> {
+        Self::load_linked(path.as_ref(), &[])
+    }
+
+    /// Loads a map that is linked to by portals in the maps in `loading`.
+    fn load_linked(path: &Path, loading: &[PathBuf]) -> crate::raycaster::Result<Self> {
+        let src = fs::read_to_string(path).map_err(|source| Error::Io {
+            path: path.to_owned(),
+            source,
+        })?;
+        let mut loading = loading.to_vec();
+        loading.push(fs::canonicalize(path).unwrap_or_else(|_| path.to_owned()));
+        let mut map = Parser {
+            lines: src.lines().collect(),
+            current: 0,
+            textures: HashMap::new(),
+            maps: HashMap::new(),
+            loading,
+        }
+        .parse_map()?;
+        map.path = Some(path.to_owned());
+        let lightmap_path = Lightmap::path_for(path);
+        if lightmap_path.exists() {
+            // A lightmap baked before the map changed no longer fits it, which
+            // must not keep the map from loading to be baked again.
+            match Lightmap::load(lightmap_path, &map) {
+                Ok(lightmap) => map.lightmap = Some(lightmap),
+                Err(err) => warn!("Ignoring the lightmap: {}", err),
+            }
+        }
+        Ok(map)
+    }
+
+    /// Saves the map. Linked maps are referred to relative to the directory
+    /// of `path`, so portals can only link to maps that are in a file.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> crate::raycaster::Result<()> {
+        let path = path.as_ref();
+        let unsaved = self
+            .portals
+            .iter()
+            .filter_map(|(index, link)| match &link.map {
+                Some(map) if map.path().is_none() => Some(*index),
+                _ => None,
+            });
+        if let Some(index) = unsaved.min() {
+            return Err(Error::UnsavedPortalTarget {
+                x: index % self.width,
+                y: index / self.width,
+            });
+        }
+        fs::write(path, self.serialize_in(path.parent())).map_err(|source| Error::Io {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    pub fn parse(src: &str) -> Result<Self, ParseError> {
+        Parser {
+            lines: src.lines().collect(),
+            current: 0,
+            textures: HashMap::new(),
+            maps: HashMap::new(),
+            loading: Vec::new(),
+        }
+        .parse_map()
+    }
+
+    /// Returns the map in the map file format. Linked maps are referred to
+    /// relative to the directory of the file the map was loaded from.
+    pub fn serialize(&self) -> String {
+        self.serialize_in(self.path.as_deref().and_then(Path::parent))
+    }
+
+    /// Like `serialize`, with paths relative to `dir`, or as they are if it
+    /// is `None`.
+    fn serialize_in(&self, dir: Option<&Path>) -> String {
+        let mut palette: Vec<(char, String)> = Vec::new();
+        let mut symbol_of = |tile: &Tile| {
+            let definition = write_tile(tile);
+            match palette.iter().find(|(_, d)| *d == definition) {
+                Some((symbol, _)) => *symbol,
+                None => {
+                    let symbol = palette_symbol(palette.len());
+                    palette.push((symbol, definition));
+                    symbol
+                }
+            }
+        };
+        let symbols: Vec<char> = self.tiles.iter().map(&mut symbol_of).collect();
+        let layers: Vec<Vec<char>> = self
+            .layers
+            .iter()
+            .map(|layer| {
+                let tiles = layer.tiles.iter();
+                tiles
+                    .map(|tile| tile.as_ref().map_or(' ', &mut symbol_of))
+                    .collect()
+            })
+            .collect();
+
+        let mut out = String::new();
+        writeln!(out, "size {} {}", self.width, self.height).unwrap();
+        writeln!(out, "wall_height {}", self.wall_height).unwrap();
+        if let Some(sky) = &self.sky {
+            out.push_str("sky ");
+            write_color(&mut out, sky);
+            out.push('\n');
+        }
+        for (symbol, definition) in &palette {
+            writeln!(out, "\ntile {}", symbol).unwrap();
+            out.push_str(definition);
+            out.push_str("end\n");
+        }
+        out.push_str("\ngrid\n");
+        for row in symbols.chunks(self.width) {
+            out.extend(row);
+            out.push('\n');
+        }
+        out.push_str("end\n");
+
+        let mut links: Vec<_> = self.portals.iter().collect();
+        links.sort_by_key(|(index, _)| **index);
+        if !links.is_empty() {
+            out.push('\n');
+        }
+        for (index, link) in links {
+            let (x, y) = (index % self.width, index / self.width);
+            let directive = format!(
+                "portal {} {} {} {} {}",
+                x,
+                y,
+                link.target.x,
+                link.target.y,
+                link.rotation.to_degrees()
+            );
+            match link.map.as_ref().map(|map| map.path()) {
+                None => writeln!(out, "{}", directive).unwrap(),
+                Some(Some(path)) => {
+                    let path = match dir {
+                        Some(dir) => relative_path(dir, path),
+                        None => path.to_owned(),
+                    };
+                    writeln!(out, "{} map {}", directive, quoted(&path.to_string_lossy())).unwrap()
+                }
+                // There is no way to refer to a map that is not in a file, so
+                // `save` refuses to write these.
+                Some(None) => {
+                    writeln!(out, "# {} links to a map without a file", directive).unwrap()
+                }
+            }
+        }
+        for (layer, symbols) in self.layers.iter().zip(layers) {
+            writeln!(out, "\nlayer {}", layer.elevation).unwrap();
+            for row in symbols.chunks(self.width) {
+                let row: String = row.iter().collect();
+                out.push_str(row.trim_end());
+                out.push('\n');
+            }
+            out.push_str("end\n");
+        }
+
+        if !self.lights.is_empty() {
+            out.push('\n');
+        }
+        for light in &self.lights {
+            let [r, g, b] = light.color;
+            writeln!(
+                out,
+                "point_light {} {} {} {} {} {} {} {}",
+                light.pos.x, light.pos.y, light.z, r, g, b, light.radius, light.intensity
+            )
+            .unwrap();
+        }
+        out
+    }
+}
+
+/// Puts `text` in quotes, escaping only the quotes and backslashes in it, which
+/// is all strings in map files can escape.
+fn quoted(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+/// Returns `path` relative to the directory `dir`, or `path` itself if
+/// either of them does not exist.
+fn relative_path(dir: &Path, path: &Path) -> PathBuf {
+    let dir = if dir.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        dir
+    };
+    let (dir, canonical) = match (fs::canonicalize(dir), fs::canonicalize(path)) {
+        (Ok(dir), Ok(path)) => (dir, path),
+        _ => return path.to_owned(),
+    };
+    let common = dir
+        .components()
+        .zip(canonical.components())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let mut relative = PathBuf::new();
+    for _ in common..dir.components().count() {
+        relative.push("..");
+    }
+    relative.extend(canonical.components().skip(common));
+    relative
+}
+
+fn palette_symbol(index: usize) -> char {
+    const SYMBOLS: &str = "#.o+=*%@&$~";
+    SYMBOLS
+        .chars()
+        .chain(('!'..='~').filter(|c| !SYMBOLS.contains(*c) && *c != '"'))
+        .chain((0xC0..).filter_map(char::from_u32))
+        .nth(index)
+        .unwrap()
+}
+
+fn write_tile(tile: &Tile) -> String {
+    let mut out = String::new();
+    out.push_str("    shape ");
+    write_shape(&mut out, &tile.shape);
+    out.push('\n');
+    for color in &tile.colors {
+        out.push_str("    color ");
+        write_color(&mut out, color);
+        out.push('\n');
+    }
+    write!(out, "    floor {} ", tile.floor_height).unwrap();
+    write_color(&mut out, &tile.floor_color);
+    write!(out, "\n    ceiling {} ", tile.ceiling_height).unwrap();
+    write_color(&mut out, &tile.ceiling_color);
+    writeln!(out, "\n    light {}", tile.light).unwrap();
+    if tile.floor_slope != Vector2::new(0.0, 0.0) {
+        let slope = tile.floor_slope;
+        writeln!(out, "    floor_slope {} {}", slope.x, slope.y).unwrap();
+    }
+    if tile.ceiling_slope != Vector2::new(0.0, 0.0) {
+        let slope = tile.ceiling_slope;
+        writeln!(out, "    ceiling_slope {} {}", slope.x, slope.y).unwrap();
+    }
+    if tile.wall_bottom != 0.0 || tile.wall_top.is_some() {
+        write!(out, "    wall {}", tile.wall_bottom).unwrap();
+        if let Some(top) = tile.wall_top {
+            write!(out, " {}", top).unwrap();
+        }
+        out.push('\n');
+    }
+    out
+}
+
+pub(super) fn write_shape(out: &mut String, shape: &Shape) {
+    match shape {
+        Shape::Void => out.push_str("void"),
+        Shape::Box => out.push_str("box"),
+        Shape::AxisAlignedBox(b) => write!(
+            out,
+            "axis_aligned_box {} {} {} {}",
+            b.min.x, b.min.y, b.max.x, b.max.y
+        )
+        .unwrap(),
+        Shape::Circle(c) => write!(out, "circle {} {} {}", c.pos.x, c.pos.y, c.radius).unwrap(),
+        Shape::Line(l) => write!(
+            out,
+            "line {} {} {} {}",
+            l.start.x, l.start.y, l.end.x, l.end.y
+        )
+        .unwrap(),
+        Shape::Door(d) => {
+            let kind = match d.kind {
+                DoorKind::Sliding => "sliding",
+                DoorKind::Swinging => "swinging",
+            };
+            write!(
+                out,
+                "door {} {} {} {} {} {}",
+                kind, d.panel.start.x, d.panel.start.y, d.panel.end.x, d.panel.end.y, d.speed
+            )
+            .unwrap()
+        }
+        Shape::OrientedBox(b) => write!(
+            out,
+            "oriented_box {} {} {} {} {}",
+            b.center.x,
+            b.center.y,
+            b.half_extents.x,
+            b.half_extents.y,
+            b.rotation.to_degrees()
+        )
+        .unwrap(),
+        Shape::Ellipse(e) => write!(
+            out,
+            "ellipse {} {} {} {} {}",
+            e.center.x,
+            e.center.y,
+            e.radii.x,
+            e.radii.y,
+            e.rotation.to_degrees()
+        )
+        .unwrap(),
+        Shape::Polygon(p) => {
+            out.push_str("polygon");
+            for v in p.vertices() {
+                write!(out, " {} {}", v.x, v.y).unwrap();
+            }
+        }
+        Shape::Portal(p) => write!(
+            out,
+            "portal {} {} {} {}",
+            p.face.start.x, p.face.start.y, p.face.end.x, p.face.end.y
+        )
+        .unwrap(),
+        Shape::Composite(parts) if parts.is_empty() => out.push_str("void"),
+        Shape::Composite(parts) => {
+            for (i, part) in parts.iter().enumerate() {
+                if i > 0 {
+                    out.push_str("\n    shape ");
+                }
+                write_shape(out, part);
+            }
+        }
+    }
+}
+
+fn write_color(out: &mut String, color: &Color) {
+    match color {
+        Color::Solid([r, g, b, a]) => write!(out, "solid {} {} {} {}", r, g, b, a).unwrap(),
+        Color::Test => out.push_str("test"),
+        Color::Test2 => out.push_str("test2"),
+        Color::Texture(texture, mapping) => {
+            write!(out, "texture {}", quoted(texture.path())).unwrap();
+            write_mapping(out, mapping);
+        }
+        Color::Animated { frames, rate } => {
+            write!(out, "animated {} [", rate).unwrap();
+            for frame in frames {
+                out.push(' ');
+                write_color(out, frame);
+            }
+            out.push_str(" ]");
+        }
+        Color::Scrolling { color, velocity } => {
+            write!(out, "scroll {} {} ", velocity.x, velocity.y).unwrap();
+            write_color(out, color);
+        }
+        Color::Reflective {
+            color,
+            reflectivity,
+        } => {
+            write!(out, "reflective {} ", reflectivity).unwrap();
+            write_color(out, color);
+        }
+    }
+}
+
+fn write_mapping(out: &mut String, mapping: &TextureMapping) {
+    let default = TextureMapping::default();
+    if mapping.wrap != default.wrap {
+        let wrap = match mapping.wrap {
+            Wrap::Repeat => "repeat",
+            Wrap::Clamp => "clamp",
+            Wrap::Mirror => "mirror",
+        };
+        write!(out, " wrap {}", wrap).unwrap();
+    }
+    if mapping.scale != default.scale {
+        write!(out, " scale {} {}", mapping.scale.x, mapping.scale.y).unwrap();
+    }
+    if mapping.offset != default.offset {
+        write!(out, " offset {} {}", mapping.offset.x, mapping.offset.y).unwrap();
+    }
+    if mapping.rotation != default.rotation {
+        write!(out, " rotate {}", mapping.rotation.to_degrees()).unwrap();
+    }
+}
+
+struct Parser<'a> {
+    lines: Vec<&'a str>,
+    current: usize,
+    textures: HashMap<String, Arc<Texture>>,
+    /// Maps linked to by portals, by their canonical path.
+    maps: HashMap<PathBuf, Arc<Map>>,
+    /// The canonical paths of this map and of the maps that link to it, to
+    /// catch maps that link to each other.
+    loading: Vec<PathBuf>,
+}
+
+/// A `portal` directive, which is applied once the grid is known.
+struct PortalDirective {
+    line: usize,
+    column: usize,
+    x: usize,
+    y: usize,
+    link: PortalLink,
+}
+
+impl PortalDirective {
+    fn error(&self, message: String) -> ParseError {
+        ParseError {
+            line: self.line,
+            column: self.column,
+            message,
+        }
+    }
+}
+
+impl<'a> Parser<'a> {
+    /// Returns the tokens of the next line that is not blank or a comment.
+    fn next_directive(&mut self) -> Result<Option<Tokens>, ParseError> {
+        while self.current < self.lines.len() {
+            let line = self.lines[self.current];
+            self.current += 1;
+            let trimmed = line.trim_start();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            return Tokens::new(line, self.current).map(Some);
+        }
+        Ok(None)
+    }
+
+    fn parse_map(mut self) -> Result<Map, ParseError> {
+        let mut size = None;
+        let mut wall_height = 1.0;
+        let mut sky = None;
+        let mut palette: HashMap<char, Tile> = HashMap::new();
+        let mut grid: Option<Vec<(char, usize, usize)>> = None;
+        let mut layers = Vec::new();
+        let mut portals = Vec::new();
+        let mut lights = Vec::new();
+
+        while let Some(mut tokens) = self.next_directive()? {
+            let keyword = tokens.word("a directive")?;
+            match keyword.text.as_str() {
+                "size" => {
+                    let column = tokens.column();
+                    let width = tokens.count("the map width")?;
+                    let height = tokens.count("the map height")?;
+                    if width == 0 || height == 0 {
+                        return Err(tokens.error_at(
+                            column,
+                            format!("the map size {}x{} is empty", width, height),
+                        ));
+                    }
+                    size = Some((width, height));
+                }
+                "wall_height" => wall_height = tokens.number("the wall height")?,
+                "sky" => sky = Some(self.parse_color(&mut tokens)?),
+                "point_light" => lights.push(PointLight {
+                    pos: Vector2::new(tokens.number("the light x")?, tokens.number("the light y")?),
+                    z: tokens.number("the light z")?,
+                    color: [
+                        tokens.number("red")?,
+                        tokens.number("green")?,
+                        tokens.number("blue")?,
+                    ],
+                    radius: tokens.number("the light radius")?,
+                    intensity: tokens.number("the light intensity")?,
+                }),
+                "portal" => {
+                    let column = tokens.column();
+                    let x = tokens.count("the portal x")?;
+                    let y = tokens.count("the portal y")?;
+                    let target =
+                        Vector2::new(tokens.count("the target x")?, tokens.count("the target y")?);
+                    let rotation = tokens.number("the rotation")?.to_radians();
+                    let map = match tokens.peek() {
+                        Some(_) => {
+                            let keyword = tokens.word("`map`")?;
+                            if keyword.text != "map" || keyword.quoted {
+                                return Err(tokens.error_at(
+                                    keyword.column,
+                                    format!("unexpected `{}`", keyword.text),
+                                ));
+                            }
+                            Some(self.parse_linked_map(&mut tokens)?)
+                        }
+                        None => None,
+                    };
+                    tokens.finish()?;
+                    portals.push(PortalDirective {
+                        line: tokens.line,
+                        column,
+                        x,
+                        y,
+                        link: PortalLink {
+                            target,
+                            rotation,
+                            map,
+                        },
+                    });
+                    continue;
+                }
+                "tile" => {
+                    let symbol = tokens.word("a tile symbol")?;
+                    let mut chars = symbol.text.chars();
+                    let c = match (chars.next(), chars.next()) {
+                        (Some(c), None) if !symbol.quoted => c,
+                        _ => {
+                            return Err(tokens.error_at(
+                                symbol.column,
+                                format!("tile symbol `{}` must be a single character", symbol.text),
+                            ))
+                        }
+                    };
+                    tokens.finish()?;
+                    if palette.contains_key(&c) {
+                        return Err(tokens.error_at(
+                            symbol.column,
+                            format!("tile `{}` is defined more than once", c),
+                        ));
+                    }
+                    let tile = self.parse_tile(&tokens)?;
+                    palette.insert(c, tile);
+                    continue;
+                }
+                "grid" => {
+                    let (width, height) = match size {
+                        Some(size) => size,
+                        None => {
+                            return Err(tokens.error_at(
+                                keyword.column,
+                                "`size` must be given before `grid`".to_owned(),
+                            ))
+                        }
+                    };
+                    tokens.finish()?;
+                    grid = Some(self.parse_grid(&tokens, width, height, false)?);
+                    continue;
+                }
+                "layer" => {
+                    let (width, height) = match size {
+                        Some(size) => size,
+                        None => {
+                            return Err(tokens.error_at(
+                                keyword.column,
+                                "`size` must be given before `layer`".to_owned(),
+                            ))
+                        }
+                    };
+                    let elevation = tokens.number("the layer elevation")?;
+                    tokens.finish()?;
+                    layers.push((elevation, self.parse_grid(&tokens, width, height, true)?));
+                    continue;
+                }
+                other => {
+                    return Err(
+                        tokens.error_at(keyword.column, format!("unknown directive `{}`", other))
+                    )
+                }
+            }
+            tokens.finish()?;
+        }
+
+        let missing = |name: &str| ParseError {
+            line: self.lines.len(),
+            column: 1,
+            message: format!("missing `{}`", name),
+        };
+        let (width, height) = size.ok_or_else(|| missing("size"))?;
+        let grid = grid.ok_or_else(|| missing("grid"))?;
+
+        let mut map = Map::new(width, height, wall_height);
+        map.sky = sky;
+        map.lights = lights;
+        let tile = |(c, line, column): (char, usize, usize)| {
+            palette.get(&c).cloned().ok_or_else(|| ParseError {
+                line,
+                column,
+                message: format!("tile `{}` is not defined", c),
+            })
+        };
+        for (i, cell) in grid.into_iter().enumerate() {
+            map.set_tile(i % width, i / width, tile(cell)?);
+        }
+        for (elevation, grid) in layers {
+            let layer = map.add_layer(elevation);
+            for (i, cell) in grid.into_iter().enumerate() {
+                if cell.0 == ' ' {
+                    continue;
+                }
+                let tile = tile(cell)?;
+                let only_in_grid = if tile.shape.door().is_some() {
+                    Some("is a door")
+                } else if matches!(tile.shape, Shape::Portal(_)) {
+                    Some("is a portal")
+                } else if tile
+                    .colors
+                    .iter()
+                    .any(|c| matches!(c, Color::Reflective { .. }))
+                {
+                    Some("has a mirror")
+                } else {
+                    None
+                };
+                if let Some(what) = only_in_grid {
+                    let (c, line, column) = cell;
+                    return Err(ParseError {
+                        line,
+                        column,
+                        message: format!("tile `{}` {}, which only works in the grid", c, what),
+                    });
+                }
+                layer.set_tile(i % width, i / width, Some(tile));
+            }
+        }
+        for portal in portals {
+            let (x, y) = (portal.x, portal.y);
+            match map.get_tile(x as i32, y as i32) {
+                Some(Tile {
+                    shape: Shape::Portal(_),
+                    ..
+                }) => {}
+                Some(_) => return Err(portal.error(format!("tile ({}, {}) is not a portal", x, y))),
+                None => return Err(portal.error(format!("tile ({}, {}) is outside the map", x, y))),
+            }
+            let target = portal.link.target;
+            let target_map = portal.link.map.as_deref().unwrap_or(&map);
+            if target.x >= target_map.width() || target.y >= target_map.height() {
+                return Err(portal.error(format!(
+                    "target ({}, {}) is outside the {}x{} map",
+                    target.x,
+                    target.y,
+                    target_map.width(),
+                    target_map.height()
+                )));
+            }
+            let (line, column) = (portal.line, portal.column);
+            map.set_portal_link(x, y, portal.link)
+                .map_err(|err| ParseError {
+                    line,
+                    column,
+                    message: err.to_string(),
+                })?;
+        }
+        Ok(map)
+    }
+
+    fn parse_tile(&mut self, header: &Tokens) -> Result<Tile, ParseError> {
+        let mut shapes = Vec::new();
+        let mut colors = Vec::new();
+        let mut floor = None;
+        let mut ceiling = None;
+        let mut light = 1.0;
+        let mut wall = (0.0, None);
+        let mut floor_slope = Vector2::new(0.0, 0.0);
+        let mut ceiling_slope = Vector2::new(0.0, 0.0);
+
+        loop {
+            let mut tokens = match self.next_directive()? {
+                Some(tokens) => tokens,
+                None => return Err(header.error_at(1, "`tile` is missing its `end`".to_owned())),
+            };
+            let keyword = tokens.word("a tile property")?;
+            match keyword.text.as_str() {
+                "shape" => {
+                    let shape = parse_shape(&mut tokens)?;
+                    let is_portal = |shape: &Shape| matches!(shape, Shape::Portal(_));
+                    if !shapes.is_empty() && (is_portal(&shape) || shapes.iter().any(is_portal)) {
+                        return Err(tokens.error_at(
+                            keyword.column,
+                            "a portal has to be the only shape of its tile".to_owned(),
+                        ));
+                    }
+                    shapes.push(shape);
+                }
+                "color" => colors.push(self.parse_color(&mut tokens)?),
+                "floor" => {
+                    let height = tokens.number("the floor height")?;
+                    floor = Some((self.parse_color(&mut tokens)?, height));
+                }
+                "ceiling" => {
+                    let height = tokens.number("the ceiling height")?;
+                    ceiling = Some((self.parse_color(&mut tokens)?, height));
+                }
+                "light" => light = tokens.number("the light level")?,
+                "floor_slope" => floor_slope = tokens.vector("the floor slope")?,
+                "ceiling_slope" => ceiling_slope = tokens.vector("the ceiling slope")?,
+                "wall" => {
+                    let bottom = tokens.number("the wall bottom")?;
+                    let top = if tokens.at_end() {
+                        None
+                    } else {
+                        let column = tokens.column();
+                        let top = tokens.number("the wall top")?;
+                        if top <= bottom {
+                            return Err(tokens.error_at(
+                                column,
+                                format!("wall top {} is not above the bottom {}", top, bottom),
+                            ));
+                        }
+                        Some(top)
+                    };
+                    wall = (bottom, top);
+                }
+                "end" => {
+                    tokens.finish()?;
+                    let missing = |name: &str| {
+                        tokens.error_at(keyword.column, format!("tile is missing `{}`", name))
+                    };
+                    let shape = match shapes.len() {
+                        0 => return Err(missing("shape")),
+                        1 => shapes.pop().unwrap(),
+                        _ => Shape::Composite(shapes),
+                    };
+                    let (floor_color, floor_height) = floor.ok_or_else(|| missing("floor"))?;
+                    let (ceiling_color, ceiling_height) =
+                        ceiling.ok_or_else(|| missing("ceiling"))?;
+                    let mut tile = Tile::try_new(
+                        shape,
+                        colors,
+                        floor_color,
+                        floor_height,
+                        ceiling_color,
+                        ceiling_height,
+                    )
+                    .map_err(|err| tokens.error_at(keyword.column, err.to_string()))?;
+                    tile.light = light;
+                    tile.floor_slope = floor_slope;
+                    tile.ceiling_slope = ceiling_slope;
+                    (tile.wall_bottom, tile.wall_top) = wall;
+                    return Ok(tile);
+                }
+                other => {
+                    return Err(tokens
+                        .error_at(keyword.column, format!("unknown tile property `{}`", other)))
+                }
+            }
+            tokens.finish()?;
+        }
+    }
+
+    /// Reads the rows of a `grid` or `layer`. Rows of a layer may be shorter
+    /// than the map is wide, and are filled up with spaces for empty places.
+    fn parse_grid(
+        &mut self,
+        header: &Tokens,
+        width: usize,
+        height: usize,
+        layer: bool,
+    ) -> Result<Vec<(char, usize, usize)>, ParseError> {
+        let name = if layer { "layer" } else { "grid" };
+        let mut grid = Vec::with_capacity(width * height);
+        for _ in 0..height {
+            let line = match self.lines.get(self.current) {
+                Some(line) => line.trim_end_matches('\r'),
+                None => {
+                    return Err(header.error_at(1, format!("`{}` must have {} rows", name, height)))
+                }
+            };
+            self.current += 1;
+            let mut row: Vec<char> = line.chars().collect();
+            if layer && row.len() < width {
+                row.resize(width, ' ');
+            }
+            if row.len() != width {
+                return Err(ParseError {
+                    line: self.current,
+                    column: std::cmp::min(row.len(), width) + 1,
+                    message: format!(
+                        "{} row has {} columns but the map is {} wide",
+                        name,
+                        row.len(),
+                        width
+                    ),
+                });
+            }
+            for (i, c) in row.into_iter().enumerate() {
+                grid.push((c, self.current, i + 1));
+            }
+        }
+
+        match self.next_directive()? {
+            Some(mut tokens) => {
+                let keyword = tokens.word("`end`")?;
+                if keyword.text != "end" || keyword.quoted {
+                    return Err(tokens.error_at(
+                        keyword.column,
+                        format!("expected `end` after {} grid rows", height),
+                    ));
+                }
+                tokens.finish()?;
+            }
+            None => return Err(header.error_at(1, format!("`{}` is missing its `end`", name))),
+        }
+        Ok(grid)
+    }
+
+    fn parse_color(&mut self, tokens: &mut Tokens) -> Result<Color, ParseError> {
+        let name = tokens.word("a color")?;
+        match name.text.as_str() {
+            "solid" => {
+                let mut color = [0.0; 4];
+                for (c, what) in color.iter_mut().zip(["red", "green", "blue", "alpha"]) {
+                    *c = tokens.number(what)?;
+                }
+                Ok(Color::Solid(color))
+            }
+            "test" => Ok(Color::Test),
+            "test2" => Ok(Color::Test2),
+            "texture" => {
+                let texture = self.parse_texture(tokens)?;
+                let mapping = parse_mapping(tokens)?;
+                Ok(Color::Texture(texture, mapping))
+            }
+            "animated" => {
+                let rate = tokens.number("the frame rate")?;
+                tokens.keyword("[")?;
+                // The brackets keep the frames apart from whatever follows,
+                // such as the frames of an animation this one is nested in.
+                let mut frames = vec![self.parse_color(tokens)?];
+                loop {
+                    match tokens.peek() {
+                        Some(token) if !token.quoted && token.text == "]" => break,
+                        Some(_) => frames.push(self.parse_color(tokens)?),
+                        None => return Err(tokens.error_at(tokens.column(), "expected `]`".into())),
+                    }
+                }
+                tokens.keyword("]")?;
+                Ok(Color::Animated { frames, rate })
+            }
+            "strip" => {
+                let texture = self.parse_texture(tokens)?;
+                let column = tokens.column();
+                let frames = tokens.count("the number of frames")?;
+                if frames == 0 {
+                    return Err(tokens.error_at(column, "a strip needs at least one frame".into()));
+                }
+                let rate = tokens.number("the frame rate")?;
+                Ok(Color::strip(texture, frames, rate))
+            }
+            "scroll" => {
+                let velocity = tokens.vector("the scroll velocity")?;
+                let color = Box::new(self.parse_color(tokens)?);
+                Ok(Color::Scrolling { color, velocity })
+            }
+            "reflective" => {
+                let reflectivity = tokens.number("the reflectivity")?;
+                let color = Box::new(self.parse_color(tokens)?);
+                Ok(Color::Reflective {
+                    color,
+                    reflectivity,
+                })
+            }
+            other => Err(tokens.error_at(name.column, format!("unknown color `{}`", other))),
+        }
+    }
+
+    /// Parses the path of a map that a portal links to and loads it, or reuses
+    /// it if the path was seen before. The path is relative to the directory
+    /// of the map that links to it.
+    fn parse_linked_map(&mut self, tokens: &mut Tokens) -> Result<Arc<Map>, ParseError> {
+        let column = tokens.column();
+        let path = tokens.string("a map path")?;
+        let resolved = match self.loading.last().and_then(|p| p.parent()) {
+            Some(dir) => dir.join(&path),
+            None => PathBuf::from(&path),
+        };
+        // A missing file is reported by `load_linked`.
+        let canonical = fs::canonicalize(&resolved).unwrap_or(resolved);
+        if !self.maps.contains_key(&canonical) {
+            if self.loading.contains(&canonical) {
+                return Err(tokens.error_at(
+                    column,
+                    format!("{} links back to a map that links to it", path),
+                ));
+            }
+            let map = Map::load_linked(&canonical, &self.loading)
+                .map_err(|err| tokens.error_at(column, format!("in {}: {}", path, err)))?;
+            self.maps.insert(canonical.clone(), Arc::new(map));
+        }
+        Ok(self.maps[&canonical].clone())
+    }
+
+    /// Parses a texture path and loads the texture, or reuses it if the path
+    /// was seen before.
+    fn parse_texture(&mut self, tokens: &mut Tokens) -> Result<Arc<Texture>, ParseError> {
+        let column = tokens.column();
+        let path = tokens.string("a texture path")?;
+        if !self.textures.contains_key(&path) {
+            let texture =
+                Texture::load(&path).map_err(|err| tokens.error_at(column, err.to_string()))?;
+            self.textures.insert(path.clone(), Arc::new(texture));
+        }
+        Ok(self.textures[&path].clone())
+    }
+}
+
+/// Parses the optional texture mapping properties that follow a texture path.
+fn parse_mapping(tokens: &mut Tokens) -> Result<TextureMapping, ParseError> {
+    let mut mapping = TextureMapping::default();
+    loop {
+        // Anything else belongs to whatever follows the texture.
+        let property = match tokens.peek() {
+            Some(token)
+                if !token.quoted
+                    && matches!(token.text.as_str(), "wrap" | "scale" | "offset" | "rotate") =>
+            {
+                tokens.word("a texture property")?
+            }
+            _ => break,
+        };
+        match property.text.as_str() {
+            "wrap" => {
+                let wrap = tokens.word("a wrap mode")?;
+                mapping.wrap = match wrap.text.as_str() {
+                    "repeat" => Wrap::Repeat,
+                    "clamp" => Wrap::Clamp,
+                    "mirror" => Wrap::Mirror,
+                    other => {
+                        return Err(
+                            tokens.error_at(wrap.column, format!("unknown wrap mode `{}`", other))
+                        )
+                    }
+                };
+            }
+            "scale" => mapping.scale = tokens.vector("the texture scale")?,
+            "offset" => mapping.offset = tokens.vector("the texture offset")?,
+            "rotate" => mapping.rotation = tokens.number("the texture rotation")?.to_radians(),
+            other => {
+                return Err(tokens.error_at(
+                    property.column,
+                    format!("unknown texture property `{}`", other),
+                ))
+            }
+        }
+    }
+    Ok(mapping)
+}
+
+fn parse_shape(tokens: &mut Tokens) -> Result<Shape, ParseError> {
+    let name = tokens.word("a shape")?;
+    match name.text.as_str() {
+        "void" => Ok(Shape::Void),
+        "box" => Ok(Shape::Box),
+        "axis_aligned_box" => Ok(Shape::AxisAlignedBox(AxisAlignedBox {
+            min: tokens.vector("the box minimum")?,
+            max: tokens.vector("the box maximum")?,
+        })),
+        "circle" => Ok(Shape::Circle(Circle {
+            pos: tokens.vector("the circle center")?,
+            radius: tokens.number("the circle radius")?,
+        })),
+        "line" => {
+            let start = tokens.vector("the line start")?;
+            let end = tokens.vector("the line end")?;
+            Ok(Shape::Line(Line::new(start, end)))
+        }
+        "oriented_box" => {
+            let center = tokens.vector("the box center")?;
+            let column = tokens.column();
+            let half_extents = tokens.vector("the box half extents")?;
+            if half_extents.x <= 0.0 || half_extents.y <= 0.0 {
+                return Err(tokens.error_at(
+                    column,
+                    format!(
+                        "box half extents {} {} must be positive",
+                        half_extents.x, half_extents.y
+                    ),
+                ));
+            }
+            Ok(Shape::OrientedBox(OrientedBox {
+                center,
+                half_extents,
+                rotation: tokens.number("the box rotation")?.to_radians(),
+            }))
+        }
+        "ellipse" => {
+            let center = tokens.vector("the ellipse center")?;
+            let column = tokens.column();
+            let radii = tokens.vector("the ellipse radii")?;
+            if radii.x <= 0.0 || radii.y <= 0.0 {
+                return Err(tokens.error_at(
+                    column,
+                    format!("ellipse radii {} {} must be positive", radii.x, radii.y),
+                ));
+            }
+            Ok(Shape::Ellipse(Ellipse {
+                center,
+                radii,
+                rotation: tokens.number("the ellipse rotation")?.to_radians(),
+            }))
+        }
+        "polygon" => {
+            let column = tokens.column();
+            let mut vertices = Vec::new();
+            while !tokens.at_end() {
+                let vertex_column = tokens.column();
+                let vertex = tokens.vector("a polygon vertex")?;
+                if !(0.0..=1.0).contains(&vertex.x) || !(0.0..=1.0).contains(&vertex.y) {
+                    return Err(tokens.error_at(
+                        vertex_column,
+                        format!(
+                            "polygon vertex ({}, {}) is outside the tile",
+                            vertex.x, vertex.y
+                        ),
+                    ));
+                }
+                // Every edge is a side with its own color, so a repeated
+                // vertex is an error rather than being dropped.
+                let repeated = if vertices.last() == Some(&vertex) {
+                    Some("the one before it")
+                } else if vertices.first() == Some(&vertex) && tokens.at_end() {
+                    Some("the first one")
+                } else {
+                    None
+                };
+                if let Some(repeated) = repeated {
+                    return Err(tokens.error_at(
+                        vertex_column,
+                        format!(
+                            "polygon vertex ({}, {}) repeats {}",
+                            vertex.x, vertex.y, repeated
+                        ),
+                    ));
+                }
+                vertices.push(vertex);
+            }
+            if vertices.len() < 3 {
+                return Err(tokens.error_at(
+                    column,
+                    format!(
+                        "a polygon needs at least 3 vertices but has {}",
+                        vertices.len()
+                    ),
+                ));
+            }
+            Ok(Shape::Polygon(Polygon::new(vertices)))
+        }
+        "portal" => {
+            let start = tokens.vector("the portal face start")?;
+            let end = tokens.vector("the portal face end")?;
+            Ok(Shape::Portal(Portal {
+                face: Line::new(start, end),
+            }))
+        }
+        "door" => {
+            let kind = tokens.word("a door kind")?;
+            let kind = match kind.text.as_str() {
+                "sliding" => DoorKind::Sliding,
+                "swinging" => DoorKind::Swinging,
+                other => {
+                    return Err(
+                        tokens.error_at(kind.column, format!("unknown door kind `{}`", other))
+                    )
+                }
+            };
+            let start = tokens.vector("the door panel start")?;
+            let end = tokens.vector("the door panel end")?;
+            Ok(Shape::Door(Door {
+                panel: Line::new(start, end),
+                kind,
+                speed: tokens.number("the door speed")?,
+            }))
+        }
+        other => Err(tokens.error_at(name.column, format!("unknown shape `{}`", other))),
+    }
+}
+
+struct Token {
+    text: String,
+    column: usize,
+    quoted: bool,
+}
+
+struct Tokens {
+    line: usize,
+    tokens: Vec<Token>,
+    next: usize,
+    end_column: usize,
+}
+
+impl Tokens {
+    fn new(line: &str, line_number: usize) -> Result<Self, ParseError> {
+        let mut tokens = Vec::new();
+        let mut chars = line.chars().enumerate().peekable();
+        while let Some(&(i, c)) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+            } else if c == '"' {
+                chars.next();
+                let mut text = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, '"')) => break,
+                        Some((_, '\\')) => match chars.next() {
+                            Some((_, c @ ('"' | '\\'))) => text.push(c),
+                            Some((j, c)) => {
+                                return Err(ParseError {
+                                    line: line_number,
+                                    column: j + 1,
+                                    message: format!("unknown escape `\\{}`", c),
+                                })
+                            }
+                            None => break,
+                        },
+                        Some((_, c)) => text.push(c),
+                        None => {
+                            return Err(ParseError {
+                                line: line_number,
+                                column: i + 1,
+                                message: "unterminated string".to_owned(),
+                            })
+                        }
+                    }
+                }
+                tokens.push(Token {
+                    text,
+                    column: i + 1,
+                    quoted: true,
+                });
+            } else {
+                let mut text = String::new();
+                while let Some(&(_, c)) = chars.peek() {
+                    if c.is_whitespace() {
+                        break;
+                    }
+                    text.push(c);
+                    chars.next();
+                }
+                tokens.push(Token {
+                    text,
+                    column: i + 1,
+                    quoted: false,
+                });
+            }
+        }
+        Ok(Self {
+            line: line_number,
+            tokens,
+            next: 0,
+            end_column: line.trim_end().chars().count() + 1,
+        })
+    }
+
+    fn error_at(&self, column: usize, message: String) -> ParseError {
+        ParseError {
+            line: self.line,
+            column,
+            message,
+        }
+    }
+
+    /// Column of the next token.
+    fn column(&self) -> usize {
+        self.tokens
+            .get(self.next)
+            .map_or(self.end_column, |token| token.column)
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.next)
+    }
+
+    fn at_end(&self) -> bool {
+        self.next >= self.tokens.len()
+    }
+
+    fn take(&mut self, what: &str) -> Result<&Token, ParseError> {
+        match self.tokens.get(self.next) {
+            Some(_) => {
+                self.next += 1;
+                Ok(&self.tokens[self.next - 1])
+            }
+            None => Err(self.error_at(self.end_column, format!("expected {}", what))),
+        }
+    }
+
+    fn word(&mut self, what: &str) -> Result<Token, ParseError> {
+        let token = self.take(what)?;
+        Ok(Token {
+            text: token.text.clone(),
+            column: token.column,
+            quoted: token.quoted,
+        })
+    }
+
+    /// Takes the next token, which has to be `keyword`.
+    fn keyword(&mut self, keyword: &str) -> Result<(), ParseError> {
+        let token = self.take(&format!("`{}`", keyword))?;
+        if token.quoted || token.text != keyword {
+            let message = format!("expected `{}` but found `{}`", keyword, token.text);
+            let column = token.column;
+            return Err(self.error_at(column, message));
+        }
+        Ok(())
+    }
+
+    fn string(&mut self, what: &str) -> Result<String, ParseError> {
+        let token = self.take(what)?;
+        if token.quoted {
+            Ok(token.text.clone())
+        } else {
+            let column = token.column;
+            Err(self.error_at(column, format!("expected {} in double quotes", what)))
+        }
+    }
+
+    fn number(&mut self, what: &str) -> Result<f64, ParseError> {
+        let token = self.take(what)?;
+        match token.text.parse() {
+            Ok(number) if !token.quoted => Ok(number),
+            _ => {
+                let message = format!("expected {} but found `{}`", what, token.text);
+                let column = token.column;
+                Err(self.error_at(column, message))
+            }
+        }
+    }
+
+    fn count(&mut self, what: &str) -> Result<usize, ParseError> {
+        let token = self.take(what)?;
+        match token.text.parse() {
+            Ok(count) if !token.quoted => Ok(count),
+            _ => {
+                let message = format!("expected {} but found `{}`", what, token.text);
+                let column = token.column;
+                Err(self.error_at(column, message))
+            }
+        }
+    }
+
+    fn vector(&mut self, what: &str) -> Result<Vector2<f64>, ParseError> {
+        Ok(Vector2::new(self.number(what)?, self.number(what)?))
+    }
+
+    fn finish(&self) -> Result<(), ParseError> {
+        match self.tokens.get(self.next) {
+            Some(token) => Err(self.error_at(token.column, format!("unexpected `{}`", token.text))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A map in the form `serialize` writes it, so it has to come back out
+    /// unchanged.
+    const MAP: &str = "size 3 2
+wall_height 1.5
+
+tile #
+    shape box
+    color test
+    color test2
+    color solid 1 0 0 1
+    color animated 2 [ solid 1 0 0 1 animated 4 [ test test2 ] ]
+    floor 0 test
+    ceiling 1.5 test
+    light 1
+end
+
+tile .
+    shape void
+    floor 0 solid 0.5 0.5 0.5 1
+    ceiling 1.5 test
+    light 0.8
+end
+
+tile o
+    shape portal 0 0.5 1 0.5
+    floor 0 test
+    ceiling 1.5 test
+    light 1
+end
+
+tile +
+    shape line 0 0.5 1 0.5
+    color test
+    color test2
+    floor 0 test
+    ceiling 1 test
+    light 1
+end
+
+grid
+#.o
+#+#
+end
+
+portal 2 0 1 0 0
+
+layer 1.5
+ .
++
+end
+
+point_light 1.5 0.5 0.8 1 0.9 0.8 4 2
+";
+
+    fn parse_error(src: &str) -> (usize, usize, String) {
+        match Map::parse(src) {
+            Ok(_) => panic!("expected a parse error"),
+            Err(err) => (err.line, err.column, err.message),
+        }
+    }
+
+    #[test]
+    fn round_trip() {
+        let map = Map::parse(MAP).unwrap();
+        assert_eq!(map.serialize(), MAP);
+    }
+
+    #[test]
+    fn round_trip_demo() {
+        let map = Map::load("maps/demo.map").unwrap();
+        let src = map.serialize();
+        assert_eq!(Map::parse(&src).unwrap().serialize(), src);
+    }
+
+    #[test]
+    fn texture_paths_with_special_characters() {
+        let dir = std::env::temp_dir().join(format!("raycaster-quoted-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let name = "it's a\ttab \"\\\u{7}.png";
+        fs::copy("textures/wall1.png", dir.join(name)).unwrap();
+        let src = MAP.replace(
+            "floor 0 solid 0.5 0.5 0.5 1",
+            &format!(
+                "floor 0 texture \"{}/it's a\ttab \\\"\\\\\u{7}.png\"",
+                dir.display()
+            ),
+        );
+        fs::write(dir.join("a.map"), &src).unwrap();
+
+        let map = Map::load(dir.join("a.map")).unwrap();
+        assert_eq!(map.serialize(), src);
+        map.save(dir.join("b.map")).unwrap();
+        let saved = Map::load(dir.join("b.map")).unwrap();
+        assert_eq!(saved.serialize(), src);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn sky() {
+        let src = MAP.replace(
+            "wall_height 1.5\n",
+            "wall_height 1.5\nsky texture \"textures/sky.png\"\n",
+        );
+        let map = Map::parse(&src).unwrap();
+        assert!(matches!(map.sky, Some(Color::Texture(..))));
+        assert_eq!(map.serialize(), src);
+    }
+
+    #[test]
+    fn layers() {
+        let map = Map::parse(MAP).unwrap();
+        let [layer] = map.layers() else {
+            panic!("expected one layer");
+        };
+        assert_eq!(layer.elevation(), 1.5);
+        assert!(layer.get_tile(0, 0).is_none());
+        assert!(matches!(layer.get_tile(1, 0).unwrap().shape, Shape::Void));
+        assert!(matches!(
+            layer.get_tile(0, 1).unwrap().shape,
+            Shape::Line(_)
+        ));
+        assert!(layer.get_tile(1, 1).is_none());
+    }
+
+    #[test]
+    fn tile_properties() {
+        let map = Map::parse(MAP).unwrap();
+        let floor = map.get_tile(1, 0).unwrap();
+        assert_eq!(floor.light, 0.8);
+    }
+
+    #[test]
+    fn slopes() {
+        let src = MAP.replace(
+            "    light 0.8\n",
+            "    light 0.8\n    floor_slope 0.25 0\n    ceiling_slope 0 -0.5\n",
+        );
+        let map = Map::parse(&src).unwrap();
+        let floor = map.get_tile(1, 0).unwrap();
+        assert_eq!(floor.floor_slope, Vector2::new(0.25, 0.0));
+        assert_eq!(floor.ceiling_slope, Vector2::new(0.0, -0.5));
+        assert_eq!(map.serialize(), src);
+    }
+
+    #[test]
+    fn wall_heights() {
+        let src = MAP.replace(
+            "    ceiling 1 test\n    light 1\n",
+            "    ceiling 1 test\n    light 1\n    wall 0.25 0.75\n",
+        );
+        let map = Map::parse(&src).unwrap();
+        let line = map.get_tile(1, 1).unwrap();
+        assert_eq!((line.wall_bottom, line.wall_top), (0.25, Some(0.75)));
+        assert_eq!(map.serialize(), src);
+
+        let src = src.replace("wall 0.25 0.75", "wall 0.5");
+        let map = Map::parse(&src).unwrap();
+        let line = map.get_tile(1, 1).unwrap();
+        assert_eq!((line.wall_bottom, line.wall_top), (0.5, None));
+        assert_eq!(map.serialize(), src);
+    }
+
+    #[test]
+    fn point_lights() {
+        let map = Map::parse(MAP).unwrap();
+        let [light] = map.lights.as_slice() else {
+            panic!("expected one light");
+        };
+        assert_eq!(light.pos, Vector2::new(1.5, 0.5));
+        assert_eq!(light.z, 0.8);
+        assert_eq!(light.color, [1.0, 0.9, 0.8]);
+        assert_eq!((light.radius, light.intensity), (4.0, 2.0));
+    }
+
+    #[test]
+    fn animated_colors() {
+        let map = Map::parse(MAP).unwrap();
+        match &map.get_tile(0, 0).unwrap().colors[3] {
+            Color::Animated { frames, rate } => {
+                assert_eq!(*rate, 2.0);
+                assert!(matches!(
+                    frames[..],
+                    [Color::Solid(_), Color::Animated { .. }]
+                ));
+            }
+            _ => panic!("expected an animated color"),
+        }
+    }
+
+    #[test]
+    fn portals() {
+        let map = Map::parse(MAP).unwrap();
+        let link = map.portal_link(2, 0).unwrap();
+        assert_eq!(link.target, Vector2::new(1, 0));
+        assert_eq!(link.rotation, 0.0);
+        assert!(link.map.is_none());
+
+        let src = MAP.replace("portal 2 0 1 0 0", "portal 2 0 1 0 90");
+        let link = Map::parse(&src)
+            .unwrap()
+            .portal_link(2, 0)
+            .cloned()
+            .unwrap();
+        assert!((link.rotation - std::f64::consts::FRAC_PI_2).abs() < 1e-12);
+    }
+
+    /// A map with a portal at (0, 0) that links to `link`.
+    fn linking_map(link: &str) -> String {
+        let portal = match link {
+            "" => String::new(),
+            link => format!("portal 0 0 1 0 0 map {:?}\n", link),
+        };
+        format!(
+            "size 2 1
+tile @
+    shape portal 0 0.5 1 0.5
+    floor 0 test
+    ceiling 1 test
+end
+tile .
+    shape void
+    floor 0 test
+    ceiling 1 test
+end
+grid
+@.
+end
+{}",
+            portal
+        )
+    }
+
+    /// Writes the maps in `files`, given as paths relative to a new directory
+    /// and the maps they link to, and returns the directory.
+    fn write_maps(name: &str, files: &[(&str, &str)]) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("raycaster-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        for (file, link) in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, linking_map(link)).unwrap();
+        }
+        dir
+    }
+
+    #[test]
+    fn linked_maps() {
+        let dir = write_maps("linked", &[("a.map", "sub/b.map"), ("sub/b.map", "")]);
+        let map = Map::load(dir.join("a.map")).unwrap();
+        let link = map.portal_link(0, 0).unwrap();
+        assert!(link.map.is_some());
+        assert!(map.serialize().contains("map \"sub/b.map\""));
+
+        fs::create_dir(dir.join("other")).unwrap();
+        map.save(dir.join("other/a.map")).unwrap();
+        let saved = fs::read_to_string(dir.join("other/a.map")).unwrap();
+        assert!(saved.contains("map \"../sub/b.map\""));
+        assert!(Map::load(dir.join("other/a.map")).is_ok());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn unsaved_linked_maps() {
+        let mut map = Map::parse(&linking_map("")).unwrap();
+        let target = Arc::new(Map::parse(&linking_map("")).unwrap());
+        let link = PortalLink {
+            target: Vector2::new(1, 0),
+            rotation: 0.0,
+            map: Some(target),
+        };
+        map.set_portal_link(0, 0, link).unwrap();
+        let path =
+            std::env::temp_dir().join(format!("raycaster-unsaved-{}.map", std::process::id()));
+        assert!(matches!(
+            map.save(&path),
+            Err(Error::UnsavedPortalTarget { x: 0, y: 0 })
+        ));
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn stale_lightmaps() {
+        let dir = std::env::temp_dir().join(format!("raycaster-stale-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("a.map");
+        fs::write(&path, MAP).unwrap();
+        let map = Map::load(&path).unwrap();
+        Lightmap::bake(&map, 2)
+            .save(Lightmap::path_for(&path))
+            .unwrap();
+        assert!(Map::load(&path).unwrap().lightmap.is_some());
+
+        // Colors do not change the light, but moving a light or a wall does.
+        let edited = MAP.replace("color test2\n", "color test\n");
+        fs::write(&path, edited).unwrap();
+        assert!(Map::load(&path).unwrap().lightmap.is_some());
+        let edited = MAP.replace("point_light 1.5 0.5", "point_light 1.5 0.25");
+        fs::write(&path, edited).unwrap();
+        assert!(Map::load(&path).unwrap().lightmap.is_none());
+        let edited = MAP.replace("shape line 0 0.5 1 0.5", "shape line 0 0.25 1 0.25");
+        fs::write(&path, edited).unwrap();
+        assert!(Map::load(&path).unwrap().lightmap.is_none());
+
+        // With another row of tiles the lightmap no longer fits.
+        let edited = MAP
+            .replace("size 3 2", "size 3 3")
+            .replace("#.o\n#+#\n", "#.o\n#+#\n###\n")
+            .replace("+\nend", "+\n#\nend");
+        fs::write(&path, edited).unwrap();
+        assert!(Map::load(&path).unwrap().lightmap.is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn maps_linking_back() {
+        let dir = write_maps(
+            "cycle",
+            &[
+                ("a.map", "./a.map"),
+                ("b.map", "sub/c.map"),
+                ("sub/c.map", "../b.map"),
+            ],
+        );
+        for file in ["a.map", "b.map"] {
+            match Map::load(dir.join(file)) {
+                Err(Error::Parse(err)) => assert!(
+                    err.message.contains("links back to a map that links to it"),
+                    "{}",
+                    err
+                ),
+                _ => panic!("expected {} to link back to itself", file),
+            }
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn error_positions() {
+        let replace = |from: &str, to: &str| {
+            assert!(MAP.contains(from));
+            parse_error(&MAP.replacen(from, to, 1))
+        };
+        assert_eq!(
+            replace("wall_height 1.5", "wall_hieght 1.5"),
+            (2, 1, "unknown directive `wall_hieght`".to_owned())
+        );
+        assert_eq!(
+            replace("floor 0 solid", "floor low solid"),
+            (
+                17,
+                11,
+                "expected the floor height but found `low`".to_owned()
+            )
+        );
+        assert_eq!(
+            replace("light 0.8", "light bright"),
+            (
+                19,
+                11,
+                "expected the light level but found `bright`".to_owned()
+            )
+        );
+        assert_eq!(
+            replace("#.o\n", "#.\n"),
+            (
+                39,
+                3,
+                "grid row has 2 columns but the map is 3 wide".to_owned()
+            )
+        );
+        assert_eq!(
+            replace("#+#\n", "#+x\n"),
+            (40, 3, "tile `x` is not defined".to_owned())
+        );
+        assert_eq!(
+            replace("portal 2 0 1 0 0", "portal 1 0 1 0 0"),
+            (43, 8, "tile (1, 0) is not a portal".to_owned())
+        );
+        assert_eq!(
+            replace("portal 2 0 1 0 0", "portal 2 0 3 0 0"),
+            (43, 8, "target (3, 0) is outside the 3x2 map".to_owned())
+        );
+        assert_eq!(
+            replace("    shape void\n", "    shape void 1\n"),
+            (16, 16, "unexpected `1`".to_owned())
+        );
+        assert_eq!(
+            replace("test2 ] ]", "test2 ]"),
+            (9, 63, "expected `]`".to_owned())
+        );
+        assert_eq!(
+            replace("animated 2 [", "animated 2"),
+            (9, 22, "expected `[` but found `solid`".to_owned())
+        );
+        assert_eq!(
+            replace(
+                "    shape line 0 0.5 1 0.5\n",
+                "    shape door sliding 0 0.5 1 0.5 1\n"
+            ),
+            (
+                47,
+                1,
+                "tile `+` is a door, which only works in the grid".to_owned()
+            )
+        );
+        assert_eq!(
+            replace("\n+\nend\n", "\no\nend\n"),
+            (
+                47,
+                1,
+                "tile `o` is a portal, which only works in the grid".to_owned()
+            )
+        );
+        assert_eq!(
+            replace(
+                "    color test2\n    floor 0 test\n    ceiling 1 test\n",
+                "    color reflective 0.5 test2\n    floor 0 test\n    ceiling 1 test\n"
+            ),
+            (
+                47,
+                1,
+                "tile `+` has a mirror, which only works in the grid".to_owned()
+            )
+        );
+        assert_eq!(
+            parse_error("size 1 1\ntile #\n    shape box\n"),
+            (2, 1, "`tile` is missing its `end`".to_owned())
+        );
+        assert_eq!(
+            parse_error("size 1 1\n  sky \"textures/sky.png"),
+            (2, 7, "unterminated string".to_owned())
+        );
+    }
+
+    /// Returns the error for a map with a tile of the shape in `shape`.
+    fn shape_error(shape: &str) -> (usize, usize, String) {
+        parse_error(&format!(
+            "size 1 1\ntile #\n    shape {}\n    color test\n    floor 0 test\n    ceiling 1 test\nend\ngrid\n#\nend\n",
+            shape
+        ))
+    }
+
+    #[test]
+    fn polygon_errors() {
+        assert_eq!(
+            shape_error("polygon 0 0 1 0"),
+            (
+                3,
+                19,
+                "a polygon needs at least 3 vertices but has 2".to_owned()
+            )
+        );
+        assert_eq!(
+            shape_error("polygon 0 0 1 0 1.5 1"),
+            (
+                3,
+                27,
+                "polygon vertex (1.5, 1) is outside the tile".to_owned()
+            )
+        );
+        assert_eq!(
+            shape_error("polygon 0 0 1 0 1 0 1 1"),
+            (
+                3,
+                27,
+                "polygon vertex (1, 0) repeats the one before it".to_owned()
+            )
+        );
+        assert_eq!(
+            shape_error("polygon 0 0 1 0 1 1 0 0"),
+            (
+                3,
+                31,
+                "polygon vertex (0, 0) repeats the first one".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn ellipse_errors() {
+        for radii in ["0 0.2", "0.4 -0.1"] {
+            let (line, column, message) = shape_error(&format!("ellipse 0.5 0.5 {} 0", radii));
+            assert_eq!((line, column), (3, 27));
+            assert_eq!(message, format!("ellipse radii {} must be positive", radii));
+        }
+    }
+
+    #[test]
+    fn oriented_box_errors() {
+        for half_extents in ["0 0.2", "0.4 -0.1"] {
+            let (line, column, message) =
+                shape_error(&format!("oriented_box 0.5 0.5 {} 0", half_extents));
+            assert_eq!((line, column), (3, 32));
+            assert_eq!(
+                message,
+                format!("box half extents {} must be positive", half_extents)
+            );
+        }
+    }
+
+    #[test]
+    fn wall_errors() {
+        assert_eq!(
+            parse_error(
+                "size 1 1\ntile .\n    shape void\n    floor 0 test\n    ceiling 1 test\n    wall 0.5 0.25\nend\n"
+            ),
+            (
+                6,
+                14,
+                "wall top 0.25 is not above the bottom 0.5".to_owned()
+            )
+        );
+    }
+
+    #[test]
+    fn empty_size() {
+        assert_eq!(
+            parse_error("size 0 4\n"),
+            (1, 6, "the map size 0x4 is empty".to_owned())
+        );
+        assert_eq!(
+            parse_error("size 4 0\n"),
+            (1, 6, "the map size 4x0 is empty".to_owned())
+        );
+    }
+}
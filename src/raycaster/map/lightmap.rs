@@ -0,0 +1,350 @@
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+
+use cgmath::{Vector2, Vector3};
+use image::{ImageBuffer, Rgb};
+
+use crate::raycaster::Error;
+
+use super::*;
+
+/// Light level that the brightest value of a lightmap file stands for.
+/// Brighter texels are clamped when saving.
+const MAX_LIGHT: f64 = 4.0;
+
+/// Light from the static lights of a map, baked into a few texels for every
+/// floor, ceiling and wall side of its tiles, in the bottom grid and in every
+/// layer.
+///
+/// The texels are laid out like an image. Every tile gets a block of
+/// `resolution` rows with `resolution` columns for its floor, its ceiling and
+/// each of its sides, in that order. The blocks of a row of tiles are placed
+/// side by side, and the rows of the bottom grid come first, followed by those
+/// of every layer. Floors and ceilings are laid out like the tile, and the
+/// rows of a wall go from its top to its bottom. The channels of the first two
+/// texels of an extra last row hold the `bake_key` of the map.
+pub struct Lightmap {
+    resolution: usize,
+    /// See `bake_key`.
+    key: u64,
+    /// Number of sides every block has room for.
+    sides: usize,
+    /// Size of the map, in tiles.
+    map_width: usize,
+    map_height: usize,
+    texels: Vec<[f64; 3]>,
+}
+
+/// The part of a `Lightmap` that belongs to one tile.
+#[derive(Clone, Copy)]
+pub struct LightmapTile<'a> {
+    lightmap: &'a Lightmap,
+    /// Index of the top left texel of the block.
+    start: usize,
+}
+
+impl Lightmap {
+    /// Bakes the light of `map.lights` with `resolution` texels along each
+    /// edge of every surface. Walls block the lights as they are at the time
+    /// of baking, so open doors stay lit after they close.
+    pub fn bake(map: &Map, resolution: usize) -> Self {
+        let grids = 1 + map.layers.len();
+        let sides = map
+            .tiles
+            .iter()
+            .chain(
+                map.layers
+                    .iter()
+                    .flat_map(|layer| layer.tiles.iter().flatten()),
+            )
+            .map(|tile| tile.shape.sides() as usize)
+            .max()
+            .unwrap_or(0);
+        let mut lightmap = Self {
+            resolution,
+            key: bake_key(map),
+            sides,
+            map_width: map.width,
+            map_height: map.height,
+            texels: vec![
+                [0.0; 3];
+                map.width * map.height * grids * resolution.pow(2) * (2 + sides)
+            ],
+        };
+
+        for grid in 0..grids {
+            let elevation = match grid {
+                0 => 0.0,
+                _ => map.layers[grid - 1].elevation,
+            };
+            for y in 0..map.height as i32 {
+                for x in 0..map.width as i32 {
+                    let map_pos = Vector2::new(x, y);
+                    let tile = match grid {
+                        0 => map.tile(map_pos),
+                        _ => map.layers[grid - 1].tile(map_pos),
+                    };
+                    if let Some(tile) = tile {
+                        lightmap.bake_tile(map, (grid, map_pos), tile, elevation);
+                    }
+                }
+            }
+        }
+        lightmap
+    }
+
+    fn bake_tile(
+        &mut self,
+        map: &Map,
+        (grid, map_pos): (usize, Vector2<i32>),
+        tile: &Tile,
+        elevation: f64,
+    ) {
+        let start = self.block_start(grid, map_pos);
+        let r = self.resolution;
+        let offset: Vector2<f64> = map_pos.cast().unwrap();
+        let bottom = elevation + tile.wall_bottom;
+        let top = elevation + tile.wall_top.unwrap_or(map.wall_height);
+        for j in 0..r {
+            for i in 0..r {
+                let texel = Vector2::new(i as f64 + 0.5, j as f64 + 0.5) / r as f64;
+                let row = start + j * self.width();
+
+                let floor = (offset + texel, elevation + tile.floor_height_at(texel));
+                self.texels[row + i] = bake_point(map, floor, Vector3::new(0.0, 0.0, 1.0));
+                let ceiling = (offset + texel, elevation + tile.ceiling_height_at(texel));
+                self.texels[row + r + i] = bake_point(map, ceiling, Vector3::new(0.0, 0.0, -1.0));
+
+                for side in 0..tile.shape.sides() {
+                    if let Some((pos, normal)) = tile.shape.surface_point(side, texel.x) {
+                        let point = (offset + pos, top - texel.y * (top - bottom));
+                        let normal = Vector3::new(normal.x, normal.y, 0.0);
+                        let column = (2 + side as usize) * r + i;
+                        self.texels[row + column] = bake_point(map, point, normal);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the file the lightmap of the map in `map_path` is stored in,
+    /// next to the map file.
+    pub fn path_for(map_path: &Path) -> PathBuf {
+        map_path.with_extension("lightmap.png")
+    }
+
+    /// Loads a lightmap baked for `map`. It has to fit the map and have been
+    /// baked from the same lights and tiles.
+    pub fn load<P: AsRef<Path>>(path: P, map: &Map) -> crate::raycaster::Result<Self> {
+        let path = path.as_ref();
+        let image = image::open(path)
+            .map_err(|source| Error::Image {
+                path: path.to_owned(),
+                source,
+            })?
+            .into_rgb16();
+        let (width, height) = (image.width() as usize, image.height() as usize);
+        let rows = map.height * (1 + map.layers.len());
+        let resolution = height.saturating_sub(1) / rows.max(1);
+        let block_width = width / map.width.max(1);
+        if resolution == 0
+            || height != rows * resolution + 1
+            || width != map.width * block_width
+            || !block_width.is_multiple_of(resolution)
+            || block_width / resolution < 2
+        {
+            return Err(Error::LightmapSize {
+                path: path.to_owned(),
+                width,
+                height,
+            });
+        }
+
+        let key_row = height as u32 - 1;
+        let [a, b, c] = image.get_pixel(0, key_row).0;
+        let d = image.get_pixel(1, key_row).0[0];
+        let key = [a, b, c, d]
+            .iter()
+            .rev()
+            .fold(0, |key, &part| key << 16 | part as u64);
+        if key != bake_key(map) {
+            return Err(Error::LightmapOutdated {
+                path: path.to_owned(),
+            });
+        }
+
+        let scale = MAX_LIGHT / u16::MAX as f64;
+        Ok(Self {
+            resolution,
+            key,
+            sides: block_width / resolution - 2,
+            map_width: map.width,
+            map_height: map.height,
+            texels: image
+                .pixels()
+                .take(width * (height - 1))
+                .map(|pixel| pixel.0.map(|c| c as f64 * scale))
+                .collect(),
+        })
+    }
+
+    /// Saves the lightmap as a 16 bit PNG image.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> crate::raycaster::Result<()> {
+        let path = path.as_ref();
+        let scale = u16::MAX as f64 / MAX_LIGHT;
+        let height = self.texels.len() / self.width().max(1);
+        let key = [0, 16, 32, 48].map(|shift| (self.key >> shift) as u16);
+        let image = ImageBuffer::from_fn(self.width() as u32, height as u32 + 1, |x, y| {
+            if y as usize == height {
+                return match x {
+                    0 => Rgb([key[0], key[1], key[2]]),
+                    1 => Rgb([key[3], 0, 0]),
+                    _ => Rgb([0; 3]),
+                };
+            }
+            let texel = self.texels[y as usize * self.width() + x as usize];
+            Rgb(texel.map(|c| (c * scale).round().clamp(0.0, u16::MAX as f64) as u16))
+        });
+        image.save(path).map_err(|source| Error::Image {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    pub fn resolution(&self) -> usize {
+        self.resolution
+    }
+
+    /// Returns the baked light of the tile at `map_pos` in `grid`, which is 0
+    /// for the bottom grid and `i + 1` for the layer `i` of `Map::layers`.
+    pub fn tile(&self, grid: usize, map_pos: Vector2<i32>) -> Option<LightmapTile<'_>> {
+        if map_pos.x < 0 || map_pos.x >= self.map_width as i32 {
+            return None;
+        }
+        if map_pos.y < 0 || map_pos.y >= self.map_height as i32 {
+            return None;
+        }
+        let start = self.block_start(grid, map_pos);
+        (start < self.texels.len()).then_some(LightmapTile {
+            lightmap: self,
+            start,
+        })
+    }
+
+    /// Width of the whole lightmap in texels.
+    fn width(&self) -> usize {
+        self.map_width * self.resolution * (2 + self.sides)
+    }
+
+    fn block_start(&self, grid: usize, map_pos: Vector2<i32>) -> usize {
+        let row = (grid * self.map_height + map_pos.y as usize) * self.resolution;
+        let column = map_pos.x as usize * self.resolution * (2 + self.sides);
+        row * self.width() + column
+    }
+}
+
+impl LightmapTile<'_> {
+    /// Baked light on the floor at `pos`, given relative to the tile.
+    pub fn floor(&self, pos: Vector2<f64>) -> [f64; 3] {
+        self.sample(0, pos)
+    }
+
+    /// Baked light on the ceiling at `pos`, given relative to the tile.
+    pub fn ceiling(&self, pos: Vector2<f64>) -> [f64; 3] {
+        self.sample(1, pos)
+    }
+
+    /// Baked light on side `side` of the shape, at the texture coordinate
+    /// `pos.x` and `pos.y` from the top of the wall to its bottom.
+    pub fn wall(&self, side: u32, pos: Vector2<f64>) -> [f64; 3] {
+        if side as usize >= self.lightmap.sides {
+            return [0.0; 3];
+        }
+        self.sample(2 + side as usize, pos)
+    }
+
+    /// Interpolates between the four closest texel centers of a surface.
+    fn sample(&self, surface: usize, pos: Vector2<f64>) -> [f64; 3] {
+        let r = self.lightmap.resolution;
+        let last = (r - 1) as f64;
+        let x = (pos.x * r as f64 - 0.5).clamp(0.0, last);
+        let y = (pos.y * r as f64 - 0.5).clamp(0.0, last);
+        let (x0, y0) = (x as usize, y as usize);
+        let (x1, y1) = ((x0 + 1).min(r - 1), (y0 + 1).min(r - 1));
+        let (tx, ty) = (x - x0 as f64, y - y0 as f64);
+
+        let width = self.lightmap.width();
+        let first = self.start + surface * r;
+        let texel = |x: usize, y: usize| self.lightmap.texels[first + y * width + x];
+        let (a, b, c, d) = (texel(x0, y0), texel(x1, y0), texel(x0, y1), texel(x1, y1));
+        let mut light = [0.0; 3];
+        for i in 0..3 {
+            let top = a[i] + (b[i] - a[i]) * tx;
+            let bottom = c[i] + (d[i] - c[i]) * tx;
+            light[i] = top + (bottom - top) * ty;
+        }
+        light
+    }
+}
+
+/// Returns a hash of everything the light is baked from: the lights and the
+/// shapes and heights of the tiles in the grid and in every layer. Colors are
+/// left out, as they do not change the light.
+fn bake_key(map: &Map) -> u64 {
+    let mut source = format!("{} {} {}\n", map.width, map.height, map.wall_height);
+    for light in &map.lights {
+        let [r, g, b] = light.color;
+        writeln!(
+            source,
+            "{} {} {} {} {} {} {} {}",
+            light.pos.x, light.pos.y, light.z, r, g, b, light.radius, light.intensity
+        )
+        .unwrap();
+    }
+    let layers = map.layers.iter().map(|layer| {
+        let tiles: Vec<_> = layer.tiles.iter().map(Option::as_ref).collect();
+        (layer.elevation, tiles)
+    });
+    let grids = std::iter::once((0.0, map.tiles.iter().map(Some).collect())).chain(layers);
+    for (elevation, tiles) in grids {
+        writeln!(source, "{}", elevation).unwrap();
+        for tile in tiles {
+            match tile {
+                Some(tile) => {
+                    file::write_shape(&mut source, &tile.shape);
+                    let (floor, ceiling) = (tile.floor_slope, tile.ceiling_slope);
+                    writeln!(
+                        source,
+                        " {} {} {} {} {} {} {} {:?}",
+                        tile.floor_height,
+                        tile.ceiling_height,
+                        floor.x,
+                        floor.y,
+                        ceiling.x,
+                        ceiling.y,
+                        tile.wall_bottom,
+                        tile.wall_top
+                    )
+                    .unwrap();
+                }
+                None => source.push('\n'),
+            }
+        }
+    }
+    // FNV-1a, which unlike the hasher of the standard library stays the same
+    // between Rust versions.
+    source.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3)
+    })
+}
+
+/// Adds up the light of every static light of `map` that reaches a point.
+fn bake_point(map: &Map, point: (Vector2<f64>, f64), normal: Vector3<f64>) -> [f64; 3] {
+    let mut total = [0.0; 3];
+    for light in &map.lights {
+        for (total, light) in total.iter_mut().zip(light.shine(map, point, normal, true)) {
+            *total += light;
+        }
+    }
+    total
+}
@@ -1,14 +1,23 @@
 use std::f64::consts::PI;
 
-use cgmath::{InnerSpace, Vector2};
+use cgmath::{InnerSpace, Matrix2, Vector2};
 
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub enum Shape {
     Void,
     Box,
     AxisAlignedBox(AxisAlignedBox),
     Circle(Circle),
     Line(Line),
+    Door(Door),
+    Portal(Portal),
+    Polygon(Polygon),
+    OrientedBox(OrientedBox),
+    Ellipse(Ellipse),
+    /// Several shapes in one tile. The sides of the parts follow each other,
+    /// so the first side of the second part comes after the last side of the
+    /// first part.
+    Composite(Vec<Shape>),
 }
 
 impl Shape {
@@ -16,17 +25,99 @@ impl Shape {
         match self {
             Self::Void => None,
             Self::Box => {
-                const b: AxisAlignedBox = AxisAlignedBox {
+                const BOX: AxisAlignedBox = AxisAlignedBox {
                     min: Vector2 { x: 0.0, y: 0.0 },
                     max: Vector2 { x: 1.0, y: 1.0 },
                 };
-                b.ray_cast(pos, dir)
+                BOX.ray_cast(pos, dir)
             }
             Self::AxisAlignedBox(shape) => shape.ray_cast(pos, dir),
             Self::Circle(shape) => shape.ray_cast(pos, dir),
             Self::Line(shape) => shape.ray_cast(pos, dir),
+            Self::Door(shape) => shape.ray_cast(pos, dir, 0.0),
+            Self::Portal(shape) => shape.ray_cast(pos, dir),
+            Self::Polygon(shape) => shape.ray_cast(pos, dir),
+            Self::OrientedBox(shape) => shape.ray_cast(pos, dir),
+            Self::Ellipse(shape) => shape.ray_cast(pos, dir),
+            Self::Composite(_) => {
+                let mut hits = Vec::new();
+                self.ray_cast_all(pos, dir, 0.0, &mut hits);
+                hits.into_iter()
+                    .min_by(|a, b| a.length.total_cmp(&b.length))
+            }
+        }
+    }
+
+    /// Adds a hit to `hits` for every part of the shape the ray hits, in no
+    /// particular order. `door_open` is how far doors in the shape are open.
+    /// Portals are left out, see `Map::ray_cast`.
+    pub fn ray_cast_all(
+        &self,
+        pos: Vector2<f64>,
+        dir: Vector2<f64>,
+        door_open: f64,
+        hits: &mut Vec<ShapeHitInfo>,
+    ) {
+        match self {
+            Self::Door(shape) => hits.extend(shape.ray_cast(pos, dir, door_open)),
+            Self::Portal(_) => {}
+            Self::Composite(shapes) => {
+                let mut first_side = 0;
+                for shape in shapes {
+                    let start = hits.len();
+                    shape.ray_cast_all(pos, dir, door_open, hits);
+                    for hit in &mut hits[start..] {
+                        hit.side += first_side;
+                    }
+                    first_side += shape.sides();
+                }
+            }
+            shape => hits.extend(shape.ray_cast(pos, dir)),
+        }
+    }
+
+    /// Returns the first door in the shape.
+    pub fn door(&self) -> Option<&Door> {
+        match self {
+            Self::Door(door) => Some(door),
+            Self::Composite(shapes) => shapes.iter().find_map(Shape::door),
+            _ => None,
+        }
+    }
+
+    /// Returns the point on side `side` at the texture coordinate `x`, and
+    /// the unit normal of the side facing away from the shape. Doors are
+    /// closed and portals have no sides.
+    pub fn surface_point(&self, side: u32, x: f64) -> Option<(Vector2<f64>, Vector2<f64>)> {
+        match self {
+            Self::Void | Self::Portal(_) => None,
+            Self::Box => {
+                const BOX: AxisAlignedBox = AxisAlignedBox {
+                    min: Vector2 { x: 0.0, y: 0.0 },
+                    max: Vector2 { x: 1.0, y: 1.0 },
+                };
+                BOX.surface_point(side, x)
+            }
+            Self::AxisAlignedBox(shape) => shape.surface_point(side, x),
+            Self::Circle(shape) => (side == 0).then(|| shape.surface_point(x)),
+            Self::Line(shape) => shape.surface_point(side, x),
+            Self::Door(shape) => shape.panel.surface_point(side, x),
+            Self::Polygon(shape) => shape.surface_point(side, x),
+            Self::OrientedBox(shape) => shape.surface_point(side, x),
+            Self::Ellipse(shape) => (side == 0).then(|| shape.surface_point(x)),
+            Self::Composite(shapes) => {
+                let mut first_side = 0;
+                for shape in shapes {
+                    if side < first_side + shape.sides() {
+                        return shape.surface_point(side - first_side, x);
+                    }
+                    first_side += shape.sides();
+                }
+                None
+            }
         }
     }
+
     pub fn sides(&self) -> u32 {
         match self {
             Self::Void => 0,
@@ -34,14 +125,85 @@ impl Shape {
             Self::AxisAlignedBox(_) => 4,
             Self::Circle(_) => 1,
             Self::Line(_) => 2,
+            Self::Door(_) => 2,
+            Self::Portal(_) => 0,
+            Self::Polygon(shape) => shape.edges.len() as u32,
+            Self::OrientedBox(_) => 4,
+            Self::Ellipse(_) => 1,
+            Self::Composite(shapes) => shapes.iter().map(Shape::sides).sum(),
+        }
+    }
+
+    /// Returns how far a circle at `center` has to be pushed to no longer
+    /// overlap the shape, or `None` if they do not overlap.
+    pub fn penetration(&self, center: Vector2<f64>, radius: f64) -> Option<Vector2<f64>> {
+        self.penetration_all(center, radius, 0.0)
+    }
+
+    /// Like `penetration`, with doors in the shape open by `door_open`. When
+    /// several parts overlap the circle, the largest push is returned.
+    pub fn penetration_all(
+        &self,
+        center: Vector2<f64>,
+        radius: f64,
+        door_open: f64,
+    ) -> Option<Vector2<f64>> {
+        match self {
+            Self::Void => None,
+            Self::Box => {
+                const BOX: AxisAlignedBox = AxisAlignedBox {
+                    min: Vector2 { x: 0.0, y: 0.0 },
+                    max: Vector2 { x: 1.0, y: 1.0 },
+                };
+                BOX.penetration(center, radius)
+            }
+            Self::AxisAlignedBox(shape) => shape.penetration(center, radius),
+            Self::Circle(shape) => shape.penetration(center, radius),
+            Self::Line(shape) => shape.penetration(center, radius),
+            Self::Door(shape) => shape.penetration(center, radius, door_open),
+            // Portals are walked through, see `Map::portal_crossing`.
+            Self::Portal(_) => None,
+            Self::Polygon(shape) => shape.penetration(center, radius),
+            Self::OrientedBox(shape) => shape.penetration(center, radius),
+            Self::Ellipse(shape) => shape.penetration(center, radius),
+            Self::Composite(shapes) => shapes
+                .iter()
+                .filter_map(|shape| shape.penetration_all(center, radius, door_open))
+                .max_by(|a, b| a.magnitude2().total_cmp(&b.magnitude2())),
         }
     }
 }
 
+/// Pushes a circle at `center` away from `point`. `fallback` is the direction
+/// used when the center is exactly on `point`.
+fn push_from_point(
+    point: Vector2<f64>,
+    center: Vector2<f64>,
+    radius: f64,
+    fallback: Vector2<f64>,
+) -> Option<Vector2<f64>> {
+    let d = center - point;
+    let dist = d.magnitude();
+    if dist >= radius {
+        None
+    } else if dist == 0.0 {
+        Some(fallback * radius)
+    } else {
+        Some(d / dist * (radius - dist))
+    }
+}
+
+/// Rotates counterclockwise by `angle` radians.
+fn rotation(angle: f64) -> Matrix2<f64> {
+    Matrix2::new(angle.cos(), angle.sin(), -angle.sin(), angle.cos())
+}
+
 pub struct ShapeHitInfo {
     pub length: f64,
     pub x: f64,
     pub side: u32,
+    /// Unit surface normal, facing towards the ray origin.
+    pub normal: Vector2<f64>,
 }
 
 #[derive(Clone, Copy)]
@@ -52,10 +214,10 @@ pub struct AxisAlignedBox {
 
 impl AxisAlignedBox {
     fn ray_cast(&self, pos: Vector2<f64>, dir: Vector2<f64>) -> Option<ShapeHitInfo> {
-        let (x, side) = if dir.x > 0.0 {
-            (self.min.x, 0)
+        let (x, side, normal) = if dir.x > 0.0 {
+            (self.min.x, 0, Vector2::new(-1.0, 0.0))
         } else {
-            (self.max.x, 1)
+            (self.max.x, 1, Vector2::new(1.0, 0.0))
         };
         let a = (x - pos.x) / dir.x;
         let y = a * dir.y + pos.y;
@@ -64,13 +226,14 @@ impl AxisAlignedBox {
                 length: a,
                 x: (y - self.min.y) / (self.max.y - self.min.y),
                 side,
+                normal,
             });
         };
 
-        let (y, side) = if dir.y > 0.0 {
-            (self.min.y, 2)
+        let (y, side, normal) = if dir.y > 0.0 {
+            (self.min.y, 2, Vector2::new(0.0, -1.0))
         } else {
-            (self.max.y, 3)
+            (self.max.y, 3, Vector2::new(0.0, 1.0))
         };
         let a = (y - pos.y) / dir.y;
         let x = a * dir.x + pos.x;
@@ -79,11 +242,47 @@ impl AxisAlignedBox {
                 length: a,
                 x: (x - self.min.x) / (self.max.x - self.min.x),
                 side,
+                normal,
             });
         };
 
         None
     }
+
+    fn surface_point(&self, side: u32, x: f64) -> Option<(Vector2<f64>, Vector2<f64>)> {
+        let along_y = self.min.y + (self.max.y - self.min.y) * x;
+        let along_x = self.min.x + (self.max.x - self.min.x) * x;
+        match side {
+            0 => Some((Vector2::new(self.min.x, along_y), Vector2::new(-1.0, 0.0))),
+            1 => Some((Vector2::new(self.max.x, along_y), Vector2::new(1.0, 0.0))),
+            2 => Some((Vector2::new(along_x, self.min.y), Vector2::new(0.0, -1.0))),
+            3 => Some((Vector2::new(along_x, self.max.y), Vector2::new(0.0, 1.0))),
+            _ => None,
+        }
+    }
+
+    fn penetration(&self, center: Vector2<f64>, radius: f64) -> Option<Vector2<f64>> {
+        let closest = Vector2::new(
+            center.x.clamp(self.min.x, self.max.x),
+            center.y.clamp(self.min.y, self.max.y),
+        );
+        if closest != center {
+            return push_from_point(closest, center, radius, Vector2::new(0.0, 0.0));
+        }
+
+        // The center is inside, so push it out through the nearest face.
+        let faces = [
+            (center.x - self.min.x, Vector2::new(-1.0, 0.0)),
+            (self.max.x - center.x, Vector2::new(1.0, 0.0)),
+            (center.y - self.min.y, Vector2::new(0.0, -1.0)),
+            (self.max.y - center.y, Vector2::new(0.0, 1.0)),
+        ];
+        let (dist, normal) = faces
+            .into_iter()
+            .min_by(|a, b| a.0.total_cmp(&b.0))
+            .unwrap();
+        Some(normal * (dist + radius))
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -104,10 +303,10 @@ impl Circle {
             let sqrt = in_sqrt.sqrt();
             let l1 = (-b - sqrt) / (2.0 * a);
             let l2 = (-b + sqrt) / (2.0 * a);
-            let l = if l1 >= 0.0 {
-                l1
+            let (l, outside) = if l1 >= 0.0 {
+                (l1, true)
             } else if l2 >= 0.0 {
-                l2
+                (l2, false)
             } else {
                 return None;
             };
@@ -122,11 +321,27 @@ impl Circle {
                 length: l,
                 x: angle / (2.0 * PI),
                 side: 0,
+                normal: if outside { p } else { -p },
             })
         } else {
             None
         }
     }
+
+    fn surface_point(&self, x: f64) -> (Vector2<f64>, Vector2<f64>) {
+        let (sin, cos) = (x * 2.0 * PI).sin_cos();
+        let normal = Vector2::new(cos, sin);
+        (self.pos + normal * self.radius, normal)
+    }
+
+    fn penetration(&self, center: Vector2<f64>, radius: f64) -> Option<Vector2<f64>> {
+        push_from_point(
+            self.pos,
+            center,
+            self.radius + radius,
+            Vector2::new(1.0, 0.0),
+        )
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -157,15 +372,418 @@ impl Line {
         let div = 1.0 / ((x1 - x2) * (y3 - y4) - (y1 - y2) * (x3 - x4));
         let t = div * ((x1 - x3) * (y3 - y4) - (y1 - y3) * (x3 - x4));
         let u = div * ((x1 - x3) * (y1 - y2) - (y1 - y3) * (x1 - x2));
-        if t + 0.001 < 0.0 || u < 0.0 || u > 1.0 {
+        if t + 0.001 < 0.0 || !(0.0..=1.0).contains(&u) {
             None
         } else {
-            let side = if self.normal.dot(dir) > 0.0 { 0 } else { 1 };
+            let (side, normal) = if self.normal.dot(dir) > 0.0 {
+                (0, -self.normal)
+            } else {
+                (1, self.normal)
+            };
             Some(ShapeHitInfo {
                 length: t,
                 x: u,
                 side,
+                normal: normal.normalize(),
+            })
+        }
+    }
+
+    /// Side 0 faces away from `normal`, like in `ray_cast`.
+    fn surface_point(&self, side: u32, x: f64) -> Option<(Vector2<f64>, Vector2<f64>)> {
+        let normal = match side {
+            0 => -self.normal,
+            1 => self.normal,
+            _ => return None,
+        };
+        Some((self.start + (self.end - self.start) * x, normal.normalize()))
+    }
+
+    fn penetration(&self, center: Vector2<f64>, radius: f64) -> Option<Vector2<f64>> {
+        let delta = self.end - self.start;
+        let t = ((center - self.start).dot(delta) / delta.magnitude2()).clamp(0.0, 1.0);
+        let closest = self.start + delta * t;
+        push_from_point(closest, center, radius, self.normal.normalize())
+    }
+}
+
+#[derive(Clone, Copy)]
+pub enum DoorKind {
+    /// The panel slides towards its end point.
+    Sliding,
+    /// The panel swings a quarter turn around its start point. Whatever
+    /// swings out of the tile is cut off.
+    Swinging,
+}
+
+/// A door panel. How far the door is open is stored in the `Map`, and is
+/// passed in when ray casting.
+#[derive(Clone, Copy)]
+pub struct Door {
+    pub panel: Line,
+    pub kind: DoorKind,
+    /// Open fraction per second.
+    pub speed: f64,
+}
+
+impl Door {
+    pub fn ray_cast(
+        &self,
+        pos: Vector2<f64>,
+        dir: Vector2<f64>,
+        open: f64,
+    ) -> Option<ShapeHitInfo> {
+        let (panel, (t0, t1)) = self.panel_at(open)?;
+        let mut info = panel.ray_cast(pos, dir)?;
+        info.x = match self.kind {
+            DoorKind::Sliding => info.x * (1.0 - open),
+            DoorKind::Swinging => t0 + info.x * (t1 - t0),
+        };
+        Some(info)
+    }
+
+    pub fn penetration(
+        &self,
+        center: Vector2<f64>,
+        radius: f64,
+        open: f64,
+    ) -> Option<Vector2<f64>> {
+        self.panel_at(open)?.0.penetration(center, radius)
+    }
+
+    /// Returns the part of the panel that blocks the tile, or `None` if the
+    /// door is fully open. A swinging panel is cut off where it leaves the
+    /// tile, and the range of the whole panel that is left is returned with
+    /// it.
+    fn panel_at(&self, open: f64) -> Option<(Line, (f64, f64))> {
+        let delta = self.panel.end - self.panel.start;
+        match self.kind {
+            DoorKind::Sliding => {
+                if open >= 1.0 {
+                    return None;
+                }
+                let panel = Line::new(self.panel.start + delta * open, self.panel.end);
+                Some((panel, (0.0, 1.0)))
+            }
+            DoorKind::Swinging => {
+                let delta = rotation(open * PI / 2.0) * delta;
+                let (t0, t1) = clip_to_tile(self.panel.start, delta)?;
+                let panel = Line::new(self.panel.start + delta * t0, self.panel.start + delta * t1);
+                Some((panel, (t0, t1)))
+            }
+        }
+    }
+}
+
+/// Returns the range of `t` in [0, 1] for which `start + delta * t` lies in
+/// the unit tile, or `None` if the segment misses the tile.
+fn clip_to_tile(start: Vector2<f64>, delta: Vector2<f64>) -> Option<(f64, f64)> {
+    let (mut t0, mut t1) = (0.0f64, 1.0f64);
+    for (p, d) in [(start.x, delta.x), (start.y, delta.y)] {
+        if d == 0.0 {
+            if !(0.0..=1.0).contains(&p) {
+                return None;
+            }
+            continue;
+        }
+        let (a, b) = ((0.0 - p) / d, (1.0 - p) / d);
+        t0 = t0.max(a.min(b));
+        t1 = t1.min(a.max(b));
+    }
+    (t0 < t1).then_some((t0, t1))
+}
+
+/// A one-sided window into another place. Rays that hit the front of `face`,
+/// the side its normal points to, continue from the tile the portal is linked
+/// to. The link is stored in the `Map`.
+#[derive(Clone, Copy)]
+pub struct Portal {
+    pub face: Line,
+}
+
+impl Portal {
+    /// Only hits on the front of the face are reported.
+    pub fn ray_cast(&self, pos: Vector2<f64>, dir: Vector2<f64>) -> Option<ShapeHitInfo> {
+        self.face.ray_cast(pos, dir).filter(|info| info.side == 1)
+    }
+
+    /// Pushes a circle out of the front of the face, for portals that cannot
+    /// be walked through. Circles behind it are not pushed.
+    pub fn penetration(&self, center: Vector2<f64>, radius: f64) -> Option<Vector2<f64>> {
+        if (center - self.face.start).dot(self.face.normal) <= 0.0 {
+            return None;
+        }
+        self.face.penetration(center, radius)
+    }
+}
+
+/// A closed outline, which may be concave. Every edge is a side, going from
+/// vertex `i` to vertex `i + 1`.
+#[derive(Clone)]
+pub struct Polygon {
+    vertices: Vec<Vector2<f64>>,
+    edges: Vec<Line>,
+}
+
+impl Polygon {
+    /// Repeated consecutive vertices are dropped, as they would make edges
+    /// without a direction.
+    pub fn new(mut vertices: Vec<Vector2<f64>>) -> Self {
+        vertices.dedup();
+        while vertices.len() > 1 && vertices.first() == vertices.last() {
+            vertices.pop();
+        }
+        let edges = (0..vertices.len())
+            .map(|i| Line::new(vertices[i], vertices[(i + 1) % vertices.len()]))
+            .collect();
+        Self { vertices, edges }
+    }
+
+    pub fn vertices(&self) -> &[Vector2<f64>] {
+        &self.vertices
+    }
+
+    fn ray_cast(&self, pos: Vector2<f64>, dir: Vector2<f64>) -> Option<ShapeHitInfo> {
+        self.edges
+            .iter()
+            .enumerate()
+            .filter_map(|(i, edge)| {
+                let info = edge.ray_cast(pos, dir)?;
+                Some(ShapeHitInfo {
+                    side: i as u32,
+                    ..info
+                })
+            })
+            .min_by(|a, b| a.length.total_cmp(&b.length))
+    }
+
+    fn surface_point(&self, side: u32, x: f64) -> Option<(Vector2<f64>, Vector2<f64>)> {
+        let edge = self.edges.get(side as usize)?;
+        let point = edge.start + (edge.end - edge.start) * x;
+        let mut outwards = edge.normal.normalize();
+        if self.contains(point + outwards * 1e-6) {
+            outwards = -outwards;
+        }
+        Some((point, outwards))
+    }
+
+    /// Uses the even-odd rule, so self-intersecting outlines have holes.
+    fn contains(&self, point: Vector2<f64>) -> bool {
+        let mut inside = false;
+        for edge in &self.edges {
+            let (a, b) = (edge.start, edge.end);
+            if (a.y > point.y) != (b.y > point.y)
+                && point.x < a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x)
+            {
+                inside = !inside;
+            }
+        }
+        inside
+    }
+
+    fn penetration(&self, center: Vector2<f64>, radius: f64) -> Option<Vector2<f64>> {
+        let (closest, edge) = self
+            .edges
+            .iter()
+            .map(|edge| {
+                let delta = edge.end - edge.start;
+                let t = ((center - edge.start).dot(delta) / delta.magnitude2()).clamp(0.0, 1.0);
+                (edge.start + delta * t, edge)
             })
+            .min_by(|(a, _), (b, _)| {
+                (a - center)
+                    .magnitude2()
+                    .total_cmp(&(b - center).magnitude2())
+            })?;
+        let d = closest - center;
+        let dist = d.magnitude();
+        if dist > 0.0 && self.contains(center) {
+            // Push the center out through the closest edge.
+            Some(d / dist * (dist + radius))
+        } else {
+            let mut outwards = edge.normal.normalize();
+            if self.contains(closest + outwards * 1e-6) {
+                outwards = -outwards;
+            }
+            push_from_point(closest, center, radius, outwards)
         }
     }
 }
+
+/// A box rotated around its center. The sides are numbered like the sides
+/// of an `AxisAlignedBox` before it is rotated.
+#[derive(Clone, Copy)]
+pub struct OrientedBox {
+    pub center: Vector2<f64>,
+    pub half_extents: Vector2<f64>,
+    /// Rotation in radians.
+    pub rotation: f64,
+}
+
+impl OrientedBox {
+    fn local_box(&self) -> AxisAlignedBox {
+        AxisAlignedBox {
+            min: -self.half_extents,
+            max: self.half_extents,
+        }
+    }
+
+    fn ray_cast(&self, pos: Vector2<f64>, dir: Vector2<f64>) -> Option<ShapeHitInfo> {
+        let to_local = rotation(-self.rotation);
+        let info = self
+            .local_box()
+            .ray_cast(to_local * (pos - self.center), to_local * dir)?;
+        Some(ShapeHitInfo {
+            normal: rotation(self.rotation) * info.normal,
+            ..info
+        })
+    }
+
+    fn surface_point(&self, side: u32, x: f64) -> Option<(Vector2<f64>, Vector2<f64>)> {
+        let (point, normal) = self.local_box().surface_point(side, x)?;
+        let to_world = rotation(self.rotation);
+        Some((to_world * point + self.center, to_world * normal))
+    }
+
+    fn penetration(&self, center: Vector2<f64>, radius: f64) -> Option<Vector2<f64>> {
+        let local = rotation(-self.rotation) * (center - self.center);
+        let push = self.local_box().penetration(local, radius)?;
+        Some(rotation(self.rotation) * push)
+    }
+}
+
+/// An ellipse rotated around its center. The texture `x` coordinate goes once
+/// around it, starting at the end of the first radius.
+#[derive(Clone, Copy)]
+pub struct Ellipse {
+    pub center: Vector2<f64>,
+    pub radii: Vector2<f64>,
+    /// Rotation in radians.
+    pub rotation: f64,
+}
+
+impl Ellipse {
+    fn ray_cast(&self, pos: Vector2<f64>, dir: Vector2<f64>) -> Option<ShapeHitInfo> {
+        // Scaling the ellipse to a unit circle keeps the length along the
+        // ray the same.
+        const UNIT: Circle = Circle {
+            pos: Vector2 { x: 0.0, y: 0.0 },
+            radius: 1.0,
+        };
+        let to_local = rotation(-self.rotation);
+        let scale = |v: Vector2<f64>| Vector2::new(v.x / self.radii.x, v.y / self.radii.y);
+        let info = UNIT.ray_cast(scale(to_local * (pos - self.center)), scale(to_local * dir))?;
+        Some(ShapeHitInfo {
+            normal: (rotation(self.rotation) * scale(info.normal)).normalize(),
+            ..info
+        })
+    }
+
+    fn surface_point(&self, x: f64) -> (Vector2<f64>, Vector2<f64>) {
+        let (sin, cos) = (x * 2.0 * PI).sin_cos();
+        let (a, b) = (self.radii.x, self.radii.y);
+        let to_world = rotation(self.rotation);
+        let point = to_world * Vector2::new(a * cos, b * sin) + self.center;
+        let normal = (to_world * Vector2::new(cos / a, sin / b)).normalize();
+        (point, normal)
+    }
+
+    fn penetration(&self, center: Vector2<f64>, radius: f64) -> Option<Vector2<f64>> {
+        let p = rotation(-self.rotation) * (center - self.center);
+        let (a, b) = (self.radii.x, self.radii.y);
+
+        // Newton's method for the angle of the closest point on the outline.
+        let mut angle = (p.y * a).atan2(p.x * b);
+        for _ in 0..8 {
+            let (sin, cos) = angle.sin_cos();
+            let f = (a * a - b * b) * sin * cos - p.x * a * sin + p.y * b * cos;
+            let df = (a * a - b * b) * (cos * cos - sin * sin) - p.x * a * cos - p.y * b * sin;
+            if df == 0.0 {
+                break;
+            }
+            angle -= f / df;
+        }
+        let (sin, cos) = angle.sin_cos();
+        let closest = Vector2::new(a * cos, b * sin);
+        let outwards = Vector2::new(cos / a, sin / b).normalize();
+
+        let inside = (p.x / a).powi(2) + (p.y / b).powi(2) < 1.0;
+        let push = if inside {
+            let dist = (closest - p).magnitude();
+            outwards * (dist + radius)
+        } else {
+            push_from_point(closest, p, radius, outwards)?
+        };
+        Some(rotation(self.rotation) * push)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn square() -> Polygon {
+        Polygon::new(vec![
+            Vector2::new(0.25, 0.25),
+            Vector2::new(0.75, 0.25),
+            Vector2::new(0.75, 0.75),
+            Vector2::new(0.25, 0.75),
+        ])
+    }
+
+    #[test]
+    fn polygon_ray_cast() {
+        let hit = square()
+            .ray_cast(Vector2::new(0.5, -1.0), Vector2::new(0.0, 1.0))
+            .unwrap();
+        assert_eq!(hit.side, 0);
+        assert!((hit.length - 1.25).abs() < 1e-9);
+        assert!((hit.x - 0.5).abs() < 1e-9);
+        assert!((hit.normal - Vector2::new(0.0, -1.0)).magnitude() < 1e-9);
+
+        let hit = square()
+            .ray_cast(Vector2::new(2.0, 0.5), Vector2::new(-1.0, 0.0))
+            .unwrap();
+        assert_eq!(hit.side, 1);
+        assert!((hit.length - 1.25).abs() < 1e-9);
+        assert!((hit.normal - Vector2::new(1.0, 0.0)).magnitude() < 1e-9);
+
+        assert!(square()
+            .ray_cast(Vector2::new(0.1, -1.0), Vector2::new(0.0, 1.0))
+            .is_none());
+    }
+
+    #[test]
+    fn polygon_penetration() {
+        assert!(square().penetration(Vector2::new(0.5, 0.0), 0.2).is_none());
+
+        let push = square().penetration(Vector2::new(0.5, 0.1), 0.2).unwrap();
+        assert!((push - Vector2::new(0.0, -0.05)).magnitude() < 1e-9);
+
+        // A center inside is pushed out through the closest edge.
+        let push = square().penetration(Vector2::new(0.7, 0.5), 0.1).unwrap();
+        assert!((push - Vector2::new(0.15, 0.0)).magnitude() < 1e-9);
+    }
+
+    #[test]
+    fn polygon_drops_repeated_vertices() {
+        let polygon = Polygon::new(vec![
+            Vector2::new(0.25, 0.25),
+            Vector2::new(0.75, 0.25),
+            Vector2::new(0.75, 0.25),
+            Vector2::new(0.75, 0.75),
+            Vector2::new(0.25, 0.75),
+            Vector2::new(0.25, 0.25),
+        ]);
+        assert_eq!(polygon.vertices(), square().vertices());
+        assert!(polygon
+            .edges
+            .iter()
+            .all(|edge| edge.normal.normalize().x.is_finite()));
+        assert!(polygon
+            .penetration(Vector2::new(0.75, 0.2), 0.1)
+            .unwrap()
+            .x
+            .is_finite());
+    }
+}
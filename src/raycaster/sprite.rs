@@ -0,0 +1,21 @@
+use cgmath::Vector2;
+
+use super::Color;
+
+#[derive(Clone)]
+pub struct Sprite {
+    pub pos: Vector2<f64>,
+    pub color: Color,
+    pub size: Vector2<f64>,
+    pub z: f64,
+}
+
+pub(crate) struct ProjectedSprite<'a> {
+    pub depth: f64,
+    pub start_x: f64,
+    pub end_x: f64,
+    pub start_y: f64,
+    pub end_y: f64,
+    pub color: &'a Color,
+    pub light: f64,
+}
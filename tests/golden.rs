@@ -0,0 +1,1008 @@
+//! Golden image tests for the renderer.
+//!
+//! Every test renders a fixed scene and compares it against
+//! `tests/golden/<name>.png`. Run with `UPDATE_GOLDEN=1` to write new
+//! reference images after an intended change in the output. On a mismatch the
+//! rendered image and a diff image are written to `target/golden/`.
+
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use cgmath::Vector2;
+use image::{Rgba, RgbaImage};
+use raycaster_cpu::raycaster::*;
+
+const WIDTH: usize = 160;
+const HEIGHT: usize = 120;
+
+/// Largest difference in a color channel that still counts as equal.
+const CHANNEL_TOLERANCE: u8 = 2;
+/// Fraction of the pixels that may differ by more than the channel tolerance.
+const PIXEL_TOLERANCE: f64 = 0.001;
+
+fn manifest_path(path: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(path)
+}
+
+fn texture() -> Arc<Texture> {
+    Arc::new(Texture::new(
+        manifest_path("textures/wall1.png").to_str().unwrap(),
+    ))
+}
+
+fn tile(shape: Shape, colors: Vec<Color>) -> Tile {
+    Tile::new(shape, colors, Color::Test, 0.0, Color::Test, 1.0)
+}
+
+/// A 7x7 room surrounded by box walls, with `center` placed at (3, 3).
+fn room(center: Tile) -> Map {
+    let size = 7;
+    let mut map = Map::new(size, size, 1.0);
+    let wall = tile(
+        Shape::Box,
+        vec![Color::Test2, Color::Test, Color::Test, Color::Test2],
+    );
+    for i in 0..size {
+        map.set_tile(i, 0, wall.clone());
+        map.set_tile(i, size - 1, wall.clone());
+        map.set_tile(0, i, wall.clone());
+        map.set_tile(size - 1, i, wall.clone());
+    }
+    map.set_tile(3, 3, center);
+    map
+}
+
+/// A camera at (3.5, 5.5) looking at the center tile.
+fn camera(z: f64) -> Camera {
+    let mut camera = Camera::new(
+        Vector2::new(3.5, 5.5),
+        -90f64.to_radians(),
+        60f64.to_radians(),
+    );
+    camera.translate_z(z);
+    camera
+}
+
+/// A dark gray 7x7 room with a pillar at (3, 3), a warm light left of the
+/// pillar and a blue one in the far corner.
+fn pillar_room() -> Map {
+    let gray = Color::Solid([0.8, 0.8, 0.8, 1.0]);
+    let mut map = Map::new(7, 7, 1.0);
+    for y in 0..7 {
+        for x in 0..7 {
+            let wall = x == 0 || y == 0 || x == 6 || y == 6 || (x, y) == (3, 3);
+            let (shape, sides) = if wall {
+                (Shape::Box, 4)
+            } else {
+                (Shape::Void, 0)
+            };
+            let mut tile = Tile::new(
+                shape,
+                vec![gray.clone(); sides],
+                gray.clone(),
+                0.0,
+                gray.clone(),
+                1.0,
+            );
+            tile.light = 0.15;
+            map.set_tile(x, y, tile);
+        }
+    }
+    map.lights = vec![
+        PointLight {
+            pos: Vector2::new(1.8, 3.5),
+            z: 0.5,
+            color: [1.0, 0.8, 0.5],
+            radius: 5.0,
+            intensity: 1.5,
+        },
+        PointLight {
+            pos: Vector2::new(5.5, 1.5),
+            z: 0.8,
+            color: [0.4, 0.6, 1.0],
+            radius: 2.5,
+            intensity: 1.0,
+        },
+    ];
+    map
+}
+
+/// A camera at (2, 5.5) in the pillar room, looking past the pillar.
+fn pillar_camera() -> Camera {
+    let mut camera = Camera::new(
+        Vector2::new(2.0, 5.5),
+        -50f64.to_radians(),
+        60f64.to_radians(),
+    );
+    camera.translate_z(0.5);
+    camera
+}
+
+fn render(camera: &Camera, map: &Map, sprites: &[Sprite]) -> RgbaImage {
+    Renderer::new(WIDTH, HEIGHT).render_image(camera, map, sprites, 0.0)
+}
+
+fn assert_golden(name: &str, actual: &RgbaImage) {
+    let reference_path = manifest_path(&format!("tests/golden/{name}.png"));
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        std::fs::create_dir_all(reference_path.parent().unwrap()).unwrap();
+        actual.save(&reference_path).unwrap();
+        return;
+    }
+
+    let reference = match image::open(&reference_path) {
+        Ok(reference) => reference.into_rgba8(),
+        Err(err) => panic!(
+            "could not open {}: {err}\nrun with UPDATE_GOLDEN=1 to create it",
+            reference_path.display()
+        ),
+    };
+    assert_eq!(
+        reference.dimensions(),
+        actual.dimensions(),
+        "{name}: size differs from the reference"
+    );
+
+    let mut diff = RgbaImage::new(actual.width(), actual.height());
+    let mut mismatched = 0;
+    for (x, y, expected) in reference.enumerate_pixels() {
+        let found = actual.get_pixel(x, y);
+        let error = (0..4)
+            .map(|i| expected[i].abs_diff(found[i]))
+            .max()
+            .unwrap();
+        if error > CHANNEL_TOLERANCE {
+            mismatched += 1;
+            diff.put_pixel(x, y, Rgba([255, 0, 0, 255]));
+        } else {
+            let gray = found[0] / 4 + found[1] / 4 + found[2] / 4;
+            diff.put_pixel(x, y, Rgba([gray, gray, gray, 255]));
+        }
+    }
+
+    let total = (actual.width() * actual.height()) as f64;
+    if mismatched as f64 > total * PIXEL_TOLERANCE {
+        let out_dir = manifest_path("target/golden");
+        std::fs::create_dir_all(&out_dir).unwrap();
+        actual
+            .save(out_dir.join(format!("{name}-actual.png")))
+            .unwrap();
+        diff.save(out_dir.join(format!("{name}-diff.png"))).unwrap();
+        panic!(
+            "{name}: {mismatched} of {total} pixels differ from {}, see {}",
+            reference_path.display(),
+            out_dir.display()
+        );
+    }
+}
+
+#[test]
+fn box_walls() {
+    let map = room(tile(Shape::Void, vec![]));
+    assert_golden("box_walls", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn larger_screen_buffer() {
+    // Only the first width * height pixels are written to.
+    let map = room(tile(Shape::Void, vec![]));
+    let mut screen = vec![7; (WIDTH * HEIGHT + 10) * 4];
+    Renderer::new(WIDTH, HEIGHT).render(&mut screen, &camera(0.5), &map, &[], 0.0);
+    let (image, rest) = screen.split_at(WIDTH * HEIGHT * 4);
+    assert!(rest.iter().all(|&c| c == 7));
+    let image = RgbaImage::from_raw(WIDTH as u32, HEIGHT as u32, image.to_vec()).unwrap();
+    assert_golden("box_walls", &image);
+}
+
+#[test]
+fn box_tile() {
+    let map = room(tile(
+        Shape::Box,
+        vec![Color::Test, Color::Test2, Color::Test, Color::Test2],
+    ));
+    assert_golden("box_tile", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn axis_aligned_box() {
+    let map = room(tile(
+        Shape::AxisAlignedBox(AxisAlignedBox {
+            min: Vector2::new(0.2, 0.3),
+            max: Vector2::new(0.7, 0.6),
+        }),
+        vec![Color::Test, Color::Test2, Color::Test, Color::Test2],
+    ));
+    assert_golden("axis_aligned_box", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn circle() {
+    let map = room(tile(
+        Shape::Circle(Circle {
+            pos: Vector2::new(0.5, 0.5),
+            radius: 0.4,
+        }),
+        vec![Color::Test],
+    ));
+    assert_golden("circle", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn line() {
+    let map = room(tile(
+        Shape::Line(Line::new(Vector2::new(0.0, 0.0), Vector2::new(1.0, 1.0))),
+        vec![Color::Test2, Color::Test],
+    ));
+    assert_golden("line", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn half_open_doors() {
+    let door = |kind| {
+        tile(
+            Shape::Door(Door {
+                panel: Line::new(Vector2::new(0.0, 0.5), Vector2::new(1.0, 0.5)),
+                kind,
+                speed: 1.0,
+            }),
+            vec![Color::Test, Color::Test2],
+        )
+    };
+    let mut map = room(door(DoorKind::Sliding));
+    map.set_tile(2, 3, door(DoorKind::Swinging));
+    map.set_door_state(3, 3, DoorState::Open).unwrap();
+    map.set_door_state(2, 3, DoorState::Open).unwrap();
+    map.update(0.5);
+    assert_golden("half_open_doors", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn textured_wall() {
+    let texture = Color::texture(texture());
+    let map = room(tile(
+        Shape::Box,
+        vec![texture.clone(), texture.clone(), texture.clone(), texture],
+    ));
+    assert_golden("textured_wall", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn translucent_walls() {
+    let mut map = room(tile(
+        Shape::Box,
+        vec![
+            Color::Solid([0.0, 0.0, 1.0, 0.5]),
+            Color::Solid([0.0, 0.0, 1.0, 0.5]),
+            Color::Solid([0.0, 0.0, 1.0, 0.5]),
+            Color::Solid([0.0, 0.0, 1.0, 0.5]),
+        ],
+    ));
+    // Like `wall3` in the demo, one side is fully transparent.
+    map.set_tile(
+        2,
+        3,
+        tile(
+            Shape::AxisAlignedBox(AxisAlignedBox {
+                min: Vector2::new(0.2, 0.2),
+                max: Vector2::new(0.8, 0.8),
+            }),
+            vec![
+                Color::Test,
+                Color::Test,
+                Color::Test,
+                Color::Solid([1.0, 1.0, 1.0, 0.0]),
+            ],
+        ),
+    );
+    assert_golden("translucent_walls", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn raised_floor() {
+    // Like `wall5` in the demo.
+    let map = room(Tile::new(
+        Shape::Void,
+        vec![],
+        Color::Test,
+        1.0,
+        Color::Test,
+        0.5,
+    ));
+    assert_golden("raised_floor", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn camera_low() {
+    let map = room(tile(
+        Shape::Box,
+        vec![Color::Test, Color::Test2, Color::Test, Color::Test2],
+    ));
+    assert_golden("camera_low", &render(&camera(0.1), &map, &[]));
+}
+
+#[test]
+fn camera_high() {
+    let map = room(tile(
+        Shape::Box,
+        vec![Color::Test, Color::Test2, Color::Test, Color::Test2],
+    ));
+    assert_golden("camera_high", &render(&camera(0.9), &map, &[]));
+}
+
+#[test]
+fn pitch_up_and_down() {
+    let map = room(tile(
+        Shape::Box,
+        vec![Color::Test, Color::Test2, Color::Test, Color::Test2],
+    ));
+    let mut camera = camera(0.5);
+    camera.look(0.3);
+    assert_golden("pitch_up", &render(&camera, &map, &[]));
+    camera.look(-0.6);
+    assert_golden("pitch_down", &render(&camera, &map, &[]));
+}
+
+#[test]
+fn sprites() {
+    let map = room(tile(Shape::Void, vec![]));
+    let sprites = [
+        Sprite {
+            pos: Vector2::new(3.2, 3.5),
+            color: Color::Test2,
+            size: Vector2::new(0.6, 0.6),
+            z: 0.0,
+        },
+        Sprite {
+            pos: Vector2::new(3.8, 2.5),
+            color: Color::texture(texture()),
+            size: Vector2::new(0.4, 0.4),
+            z: 0.4,
+        },
+    ];
+    assert_golden("sprites", &render(&camera(0.5), &map, &sprites));
+}
+
+#[test]
+fn fog_and_light() {
+    let mut map = room(tile(
+        Shape::Box,
+        vec![Color::Test, Color::Test2, Color::Test, Color::Test2],
+    ));
+    let mut dark = tile(Shape::Void, vec![]);
+    dark.light = 0.3;
+    map.set_tile(2, 4, dark);
+
+    let mut renderer = Renderer::new(WIDTH, HEIGHT);
+    renderer.lighting = Lighting {
+        fog: Fog {
+            color: [0.5, 0.5, 0.6],
+            falloff: Falloff::Linear {
+                start: 1.0,
+                end: 6.0,
+            },
+        },
+        side_shade: 0.6,
+        ..Lighting::default()
+    };
+    let image = renderer.render_image(&camera(0.5), &map, &[], 0.0);
+    assert_golden("fog_and_light", &image);
+}
+
+#[test]
+fn texture_filters() {
+    let texture = Color::texture(texture());
+    let mut map = room(tile(
+        Shape::Box,
+        vec![
+            texture.clone(),
+            texture.clone(),
+            texture.clone(),
+            texture.clone(),
+        ],
+    ));
+    for y in 1..6 {
+        for x in 1..6 {
+            if (x, y) != (3, 3) {
+                map.set_tile(
+                    x,
+                    y,
+                    Tile::new(Shape::Void, vec![], texture.clone(), 0.0, Color::Test, 1.0),
+                );
+            }
+        }
+    }
+
+    let mut renderer = Renderer::new(WIDTH, HEIGHT);
+    for (name, filter) in [
+        ("filter_bilinear", Filter::Bilinear),
+        ("filter_trilinear", Filter::Trilinear),
+    ] {
+        renderer.filter = filter;
+        let image = renderer.render_image(&camera(0.5), &map, &[], 0.0);
+        assert_golden(name, &image);
+    }
+}
+
+#[test]
+fn texture_mapping() {
+    let mapped = |wrap, scale, offset, rotation: f64| {
+        Color::Texture(
+            texture(),
+            TextureMapping {
+                wrap,
+                scale,
+                offset,
+                rotation: rotation.to_radians(),
+            },
+        )
+    };
+    let block = |color: Color| tile(Shape::Box, vec![color; 4]);
+
+    let mut map = room(block(mapped(
+        Wrap::Repeat,
+        Vector2::new(2.0, 2.0),
+        Vector2::new(0.0, 0.0),
+        0.0,
+    )));
+    map.set_tile(
+        2,
+        3,
+        block(mapped(
+            Wrap::Clamp,
+            Vector2::new(2.0, 2.0),
+            Vector2::new(-0.5, -0.5),
+            0.0,
+        )),
+    );
+    map.set_tile(
+        4,
+        3,
+        block(mapped(
+            Wrap::Mirror,
+            Vector2::new(2.0, 1.0),
+            Vector2::new(0.0, 0.0),
+            0.0,
+        )),
+    );
+    map.set_tile(
+        3,
+        4,
+        Tile::new(
+            Shape::Void,
+            vec![],
+            mapped(
+                Wrap::Repeat,
+                Vector2::new(1.0, 1.0),
+                Vector2::new(0.25, 0.0),
+                90.0,
+            ),
+            0.0,
+            Color::Test,
+            1.0,
+        ),
+    );
+    assert_golden("texture_mapping", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn animated_colors() {
+    let scrolling = Color::Scrolling {
+        color: Box::new(Color::texture(texture())),
+        velocity: Vector2::new(0.5, 0.0),
+    };
+    let mut map = room(tile(Shape::Box, vec![scrolling; 4]));
+    map.set_tile(
+        3,
+        4,
+        Tile::new(
+            Shape::Void,
+            vec![],
+            Color::Animated {
+                frames: vec![
+                    Color::Test,
+                    Color::Test2,
+                    Color::Solid([0.2, 0.6, 0.2, 1.0]),
+                ],
+                rate: 2.0,
+            },
+            0.0,
+            Color::Test,
+            1.0,
+        ),
+    );
+
+    let mut renderer = Renderer::new(WIDTH, HEIGHT);
+    for (name, time) in [("animated_start", 0.0), ("animated_later", 1.25)] {
+        let image = renderer.render_image(&camera(0.5), &map, &[], time);
+        assert_golden(name, &image);
+    }
+}
+
+/// Like `room`, with no ceiling over the floor and a sky above it.
+fn open_room(center: Tile) -> Map {
+    let mut map = room(center.clone());
+    let open = Tile::new(
+        Shape::Void,
+        vec![],
+        Color::Test,
+        0.0,
+        Color::Solid([0.0, 0.0, 0.0, 0.0]),
+        1.0,
+    );
+    for y in 1..6 {
+        for x in 1..6 {
+            map.set_tile(x, y, open.clone());
+        }
+    }
+    map.set_tile(3, 3, center);
+    map.sky = Some(Color::Test);
+    map
+}
+
+#[test]
+fn sky() {
+    let map = open_room(Tile::new(
+        Shape::Void,
+        vec![],
+        Color::Test,
+        0.0,
+        Color::Solid([0.0, 0.0, 0.0, 0.0]),
+        1.0,
+    ));
+    let mut camera = camera(0.5);
+    camera.look(0.2);
+    assert_golden("sky", &render(&camera, &map, &[]));
+}
+
+#[test]
+fn sky_in_mirrors() {
+    let mirror = Color::Reflective {
+        color: Box::new(Color::Solid([0.6, 0.7, 0.9, 1.0])),
+        reflectivity: 0.8,
+    };
+    // A mirror, turned so that it shows the sky to the side.
+    let pillar = tile(
+        Shape::Line(Line::new(Vector2::new(0.0, 0.0), Vector2::new(1.0, 1.0))),
+        vec![mirror; 2],
+    );
+    let map = open_room(pillar);
+    let mut camera = camera(0.5);
+    camera.look(0.2);
+    assert_golden("sky_in_mirrors", &render(&camera, &map, &[]));
+}
+
+#[test]
+fn portals() {
+    let portal = tile(
+        Shape::Portal(Portal {
+            face: Line::new(Vector2::new(0.0, 0.5), Vector2::new(1.0, 0.5)),
+        }),
+        vec![],
+    );
+    let mut map = room(tile(Shape::Void, vec![]));
+    map.set_tile(2, 3, portal.clone());
+    map.set_tile(4, 3, portal);
+    map.set_tile(
+        1,
+        1,
+        tile(Shape::Box, vec![Color::Solid([0.8, 0.2, 0.2, 1.0]); 4]),
+    );
+
+    let mut other = Map::new(3, 3, 1.0);
+    for (x, y) in [(0, 0), (1, 0), (2, 0), (0, 1), (2, 1), (0, 2), (2, 2)] {
+        other.set_tile(
+            x,
+            y,
+            tile(Shape::Box, vec![Color::Solid([0.2, 0.2, 0.8, 1.0]); 4]),
+        );
+    }
+
+    map.set_portal_link(
+        2,
+        3,
+        PortalLink {
+            target: Vector2::new(1, 3),
+            rotation: 90f64.to_radians(),
+            map: None,
+        },
+    )
+    .unwrap();
+    map.set_portal_link(
+        4,
+        3,
+        PortalLink {
+            target: Vector2::new(1, 2),
+            rotation: 0.0,
+            map: Some(Arc::new(other)),
+        },
+    )
+    .unwrap();
+    assert_golden("portals", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn mirrors() {
+    let mirror = |reflectivity| Color::Reflective {
+        color: Box::new(Color::Solid([0.6, 0.7, 0.9, 1.0])),
+        reflectivity,
+    };
+    let mut map = room(tile(Shape::Box, vec![mirror(0.8); 4]));
+    map.set_tile(
+        4,
+        3,
+        tile(
+            Shape::Circle(Circle {
+                pos: Vector2::new(0.5, 0.5),
+                radius: 0.4,
+            }),
+            vec![mirror(0.5)],
+        ),
+    );
+    // Behind the camera, so it is only seen in the mirrors.
+    map.set_tile(
+        2,
+        5,
+        tile(Shape::Box, vec![Color::Solid([0.8, 0.2, 0.2, 1.0]); 4]),
+    );
+    assert_golden("mirrors", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn partial_mirrors() {
+    let mirror = Color::Reflective {
+        color: Box::new(Color::Solid([0.6, 0.7, 0.9, 1.0])),
+        reflectivity: 0.8,
+    };
+    // A waist high mirror, with the far wall seen above it.
+    let mut low = tile(Shape::Box, vec![mirror.clone(); 4]);
+    low.wall_top = Some(0.4);
+    let mut map = room(low);
+    // A mirror hanging from the ceiling, reflecting the one below it.
+    let mut high = tile(
+        Shape::Line(Line::new(Vector2::new(0.0, 0.5), Vector2::new(1.0, 0.5))),
+        vec![mirror; 2],
+    );
+    high.wall_bottom = 0.6;
+    map.set_tile(3, 1, high);
+    map.set_tile(
+        2,
+        5,
+        tile(Shape::Box, vec![Color::Solid([0.8, 0.2, 0.2, 1.0]); 4]),
+    );
+    assert_golden("partial_mirrors", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn sprites_behind_mirrors() {
+    let mirror = Color::Reflective {
+        color: Box::new(Color::Solid([0.6, 0.7, 0.9, 1.0])),
+        reflectivity: 0.8,
+    };
+    let map = room(tile(Shape::Box, vec![mirror; 4]));
+    let sprite = |x, y| Sprite {
+        pos: Vector2::new(x, y),
+        color: Color::Test2,
+        size: Vector2::new(0.6, 0.6),
+        z: 0.0,
+    };
+    // The second one is hidden by the mirror.
+    let sprites = [sprite(2.9, 4.5), sprite(3.5, 2.5)];
+    assert_golden(
+        "sprites_behind_mirrors",
+        &render(&camera(0.5), &map, &sprites),
+    );
+}
+
+#[test]
+fn polygon() {
+    // An arrow head, so one corner is concave.
+    let vertices = vec![
+        Vector2::new(0.5, 0.1),
+        Vector2::new(0.9, 0.9),
+        Vector2::new(0.5, 0.6),
+        Vector2::new(0.1, 0.9),
+    ];
+    let map = room(tile(
+        Shape::Polygon(Polygon::new(vertices)),
+        vec![
+            Color::Test,
+            Color::Test2,
+            Color::Solid([0.8, 0.2, 0.2, 1.0]),
+            Color::Solid([0.2, 0.2, 0.8, 1.0]),
+        ],
+    ));
+    assert_golden("polygon", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn oriented_box() {
+    let map = room(tile(
+        Shape::OrientedBox(OrientedBox {
+            center: Vector2::new(0.5, 0.5),
+            half_extents: Vector2::new(0.35, 0.2),
+            rotation: 30f64.to_radians(),
+        }),
+        vec![
+            Color::Test,
+            Color::Test2,
+            Color::Solid([0.8, 0.2, 0.2, 1.0]),
+            Color::texture(texture()),
+        ],
+    ));
+    assert_golden("oriented_box", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn ellipse() {
+    let map = room(tile(
+        Shape::Ellipse(Ellipse {
+            center: Vector2::new(0.5, 0.5),
+            radii: Vector2::new(0.45, 0.2),
+            rotation: -20f64.to_radians(),
+        }),
+        vec![Color::texture(texture())],
+    ));
+    assert_golden("ellipse", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn composite() {
+    // Two pillars between a translucent pane and a thin wall, all seen
+    // through each other.
+    let glass = Color::Solid([0.8, 0.2, 0.2, 0.4]);
+    let shape = Shape::Composite(vec![
+        Shape::AxisAlignedBox(AxisAlignedBox {
+            min: Vector2::new(0.1, 0.75),
+            max: Vector2::new(0.9, 0.8),
+        }),
+        Shape::Circle(Circle {
+            pos: Vector2::new(0.3, 0.4),
+            radius: 0.12,
+        }),
+        Shape::Circle(Circle {
+            pos: Vector2::new(0.7, 0.4),
+            radius: 0.12,
+        }),
+        Shape::Line(Line::new(Vector2::new(0.0, 0.1), Vector2::new(1.0, 0.1))),
+    ]);
+    let mut colors = vec![glass; 4];
+    colors.extend([
+        Color::Test,
+        Color::Test2,
+        Color::Solid([0.2, 0.2, 0.8, 1.0]),
+        Color::Solid([0.2, 0.2, 0.8, 1.0]),
+    ]);
+    let map = room(tile(shape, colors));
+    assert_golden("composite", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn wall_heights() {
+    // A raised platform with a low railing on one side and a lintel on the
+    // other.
+    let mut platform = Tile::new(
+        Shape::Box,
+        vec![Color::Solid([0.2, 0.6, 0.3, 1.0]); 4],
+        Color::Solid([0.5, 0.8, 0.5, 1.0]),
+        0.35,
+        Color::Test,
+        1.0,
+    );
+    platform.wall_top = Some(0.35);
+    let mut map = room(platform);
+
+    let mut railing = tile(
+        Shape::Line(Line::new(Vector2::new(0.0, 0.5), Vector2::new(1.0, 0.5))),
+        vec![Color::Solid([0.8, 0.2, 0.2, 1.0]); 2],
+    );
+    railing.wall_top = Some(0.2);
+    map.set_tile(4, 3, railing);
+
+    let mut lintel = tile(Shape::Box, vec![Color::texture(texture()); 4]);
+    lintel.wall_bottom = 0.7;
+    map.set_tile(2, 3, lintel);
+    assert_golden("wall_heights", &render(&camera(0.5), &map, &[]));
+}
+
+/// A room two storeys high, with a balcony on the upper storey along the far
+/// wall.
+fn balcony_hall() -> Map {
+    let clear = Color::Solid([0.0, 0.0, 0.0, 0.0]);
+    let mut map = room(tile(Shape::Void, vec![]));
+    let floor = Color::Solid([0.3, 0.3, 0.6, 1.0]);
+    for y in 1..6 {
+        for x in 1..6 {
+            let ceiling = if y < 3 {
+                (Color::Solid([0.5, 0.5, 0.5, 1.0]), 1.0)
+            } else {
+                (clear.clone(), 1.0)
+            };
+            let below = Tile::new(
+                Shape::Void,
+                vec![],
+                floor.clone(),
+                0.0,
+                ceiling.0,
+                ceiling.1,
+            );
+            map.set_tile(x, y, below);
+        }
+    }
+
+    let walls = map.get_tile(0, 0).unwrap();
+    let layer = map.add_layer(1.0);
+    for y in 0..7 {
+        for x in 0..7 {
+            let tile = if x == 0 || y == 0 || x == 6 || y == 6 {
+                walls.clone()
+            } else if y < 3 {
+                let mut balcony =
+                    Tile::new(Shape::Void, vec![], Color::Test2, 0.0, Color::Test, 1.0);
+                if y == 2 {
+                    balcony.shape =
+                        Shape::Line(Line::new(Vector2::new(0.0, 1.0), Vector2::new(1.0, 1.0)));
+                    balcony.colors = vec![Color::Solid([0.8, 0.2, 0.2, 1.0]); 2];
+                    balcony.wall_top = Some(0.2);
+                }
+                balcony
+            } else {
+                Tile::new(Shape::Void, vec![], clear.clone(), 0.0, Color::Test, 1.0)
+            };
+            layer.set_tile(x, y, Some(tile));
+        }
+    }
+    map
+}
+
+#[test]
+fn storeys() {
+    let map = balcony_hall();
+    assert_golden("storeys_below", &render(&camera(0.5), &map, &[]));
+
+    let mut camera = Camera::new(
+        Vector2::new(3.5, 2.3),
+        90f64.to_radians(),
+        60f64.to_radians(),
+    );
+    camera.translate_z(1.8);
+    camera.look(-0.4);
+    assert_golden("storeys_above", &render(&camera, &map, &[]));
+}
+
+#[test]
+fn slopes() {
+    // A ramp up to a raised floor, under a ceiling that rises to the right.
+    let mut map = room(tile(Shape::Void, vec![]));
+    for y in 1..6 {
+        for x in 1..6 {
+            let mut tile = Tile::new(
+                Shape::Void,
+                vec![],
+                Color::texture(texture()),
+                0.0,
+                Color::Test,
+                0.8 + 0.1 * (x - 1) as f64,
+            );
+            tile.ceiling_slope = Vector2::new(0.1, 0.0);
+            if y == 3 {
+                tile.floor_height = 0.4;
+                tile.floor_slope = Vector2::new(0.0, -0.4);
+            } else if y < 3 {
+                tile.floor_height = 0.4;
+            }
+            map.set_tile(x, y, tile);
+        }
+    }
+    assert_golden("slopes", &render(&camera(0.5), &map, &[]));
+}
+
+#[test]
+fn point_lights() {
+    // The lights of the pillar room, rendered once without and once with
+    // shadows.
+    let map = pillar_room();
+    let mut renderer = Renderer::new(WIDTH, HEIGHT);
+    renderer.lighting.lights = map.lights.clone();
+    let image = renderer.render_image(&pillar_camera(), &map, &[], 0.0);
+    assert_golden("point_lights", &image);
+
+    renderer.lighting.shadows = true;
+    let image = renderer.render_image(&pillar_camera(), &map, &[], 0.0);
+    assert_golden("point_lights_shadows", &image);
+}
+
+#[test]
+fn lights_behind_portals() {
+    // A light in front of a portal to another map, which it does not light
+    // because it is not in that map.
+    let portal = tile(
+        Shape::Portal(Portal {
+            face: Line::new(Vector2::new(0.0, 0.5), Vector2::new(1.0, 0.5)),
+        }),
+        vec![],
+    );
+    let mut map = room(portal);
+    let mut other = Map::new(3, 3, 1.0);
+    for (x, y) in [(0, 0), (1, 0), (2, 0), (0, 1), (2, 1), (0, 2), (2, 2)] {
+        other.set_tile(
+            x,
+            y,
+            tile(Shape::Box, vec![Color::Solid([0.2, 0.2, 0.8, 1.0]); 4]),
+        );
+    }
+    map.set_portal_link(
+        3,
+        3,
+        PortalLink {
+            target: Vector2::new(1, 2),
+            rotation: 0.0,
+            map: Some(Arc::new(other)),
+        },
+    )
+    .unwrap();
+
+    let mut renderer = Renderer::new(WIDTH, HEIGHT);
+    renderer.lighting.lights = vec![PointLight {
+        pos: Vector2::new(3.5, 4.5),
+        z: 0.5,
+        color: [1.0, 0.8, 0.5],
+        radius: 4.0,
+        intensity: 2.0,
+    }];
+    let image = renderer.render_image(&camera(0.5), &map, &[], 0.0);
+    assert_golden("lights_behind_portals", &image);
+}
+
+#[test]
+fn lightmap() {
+    // The lights of the pillar room baked, rendered from a lightmap that
+    // went through a file.
+    let mut map = pillar_room();
+    let path = manifest_path("target/golden/pillar_room.lightmap.png");
+    std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+    Lightmap::bake(&map, 8).save(&path).unwrap();
+    map.lightmap = Some(Lightmap::load(&path, &map).unwrap());
+    assert_golden("lightmap", &render(&pillar_camera(), &map, &[]));
+}
+
+#[test]
+fn projections() {
+    // A circle pillar in front of a row of thin walls that runs parallel to
+    // the projection plane, so the walls have a straight top edge in the
+    // planar projection, and a sprite off to the side.
+    let mut map = room(tile(
+        Shape::Circle(Circle {
+            pos: Vector2::new(0.5, 0.5),
+            radius: 0.4,
+        }),
+        vec![Color::Test],
+    ));
+    for x in 1..6 {
+        map.set_tile(
+            x,
+            2,
+            tile(
+                Shape::Line(Line::new(Vector2::new(0.0, 0.5), Vector2::new(1.0, 0.5))),
+                vec![Color::Test2, Color::Test2],
+            ),
+        );
+    }
+    let sprites = [Sprite {
+        pos: Vector2::new(1.5, 4.0),
+        color: Color::Test2,
+        size: Vector2::new(0.5, 0.5),
+        z: 0.0,
+    }];
+
+    let mut camera = Camera::new(
+        Vector2::new(3.5, 5.5),
+        -90f64.to_radians(),
+        120f64.to_radians(),
+    );
+    camera.translate_z(0.5);
+    assert_golden("projection_planar", &render(&camera, &map, &sprites));
+    camera.set_projection(Projection::Cylindrical);
+    assert_golden("projection_cylindrical", &render(&camera, &map, &sprites));
+    camera.set_projection(Projection::Fisheye);
+    assert_golden("projection_fisheye", &render(&camera, &map, &sprites));
+}